@@ -0,0 +1,178 @@
+use std::time::{Duration, Instant};
+
+use crate::instruction::{Instruction, Dest, Src, Size::{B, Q}, Count, Label, GlobalId, Ordering, Signedness::Unsigned};
+use crate::interpreter_fn::InterpreterFn;
+use crate::object::{Object, Blob};
+
+const ITERATIONS: u64 = 500_000;
+const RUNS: usize = 5;
+
+// each takes the number of iterations as arg0
+type Program = fn() -> Object;
+const PROGRAMS: [(&str, Program); 4] = [
+    ("sum_loop", sum_loop),
+    ("copies", copies),
+    ("switch", switch),
+    ("atomics", atomics),
+];
+
+// `pinkdrone bench`: times InterpreterFn::run against the same code compiled with InterpreterFn::compile.
+// each time is the median of RUNS runs
+pub fn bench() {
+    println!("{:10} {:>12} {:>12} {:>8}", "", "run ns/ins", "compiled", "speedup");
+    for (name, program) in PROGRAMS {
+        let interp = InterpreterFn::new(program(), 1024);
+        let compiled = interp.compile().expect("the programs run in the default mode");
+        let n_instructions = instructions_run(program(), ITERATIONS);
+
+        let (expected, interp_time) = median(|| interp.run(ITERATIONS, 0, 0, 0, 0, 0).unwrap());
+        let (result, compiled_time) = median(|| compiled.run(ITERATIONS, 0, 0, 0, 0, 0).unwrap());
+        assert_eq!(result, expected, "the compiled {} got a different answer", name);
+
+        let per_instruction = |time: Duration| time.as_nanos() as f64 / n_instructions as f64;
+        println!(
+            "{:10} {:>12.1} {:>12.1} {:>7.1}x",
+            name, per_instruction(interp_time), per_instruction(compiled_time), interp_time.as_secs_f64() / compiled_time.as_secs_f64()
+        );
+    }
+}
+
+fn median(f: impl Fn() -> u64) -> (u64, Duration) {
+    let mut times = vec![];
+    let mut result = 0;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        result = f();
+        times.push(start.elapsed());
+    }
+    times.sort();
+    (result, times[RUNS / 2])
+}
+
+// how many instructions a run takes, from profiling a separate interpreter
+fn instructions_run(object: Object, n: u64) -> u64 {
+    let mut interp = InterpreterFn::new(object, 1024);
+    interp.set_profiling(true);
+    interp.run(n, 0, 0, 0, 0, 0).unwrap();
+    interp.instruction_counts().expect("it's profiling").iter().sum()
+}
+
+// adds up n, n-1, ..., 1 on the stack, and reads and writes the sum through a pointer as well
+fn sum_loop() -> Object {
+    let nowhere = Dest::Nowhere;
+    Object {
+        instructions: vec![
+            Instruction::FFIBegin(32, [Dest::Here(-8, Q), nowhere, nowhere, nowhere, nowhere, nowhere]),
+            Instruction::Copy(Dest::Here(-16, Q), Src::Imm(0), Count(1)),
+            Instruction::Copy(Dest::Here(-24, Q), Src::AddrOf(-16), Count(1)),
+            Instruction::Label(Label(0)),
+            Instruction::AddChecked(Dest::Here(-16, Q), Src::Here(-16, Q), Src::Here(-8, Q), Unsigned, Label(1)),
+            Instruction::SubChecked(Dest::Here(-8, Q), Src::Here(-8, Q), Src::Imm(1), Unsigned, Label(1)),
+            Instruction::AddChecked(Dest::Ptr(-24, 0, Q), Src::Ptr(-24, 0, Q), Src::Imm(0), Unsigned, Label(1)),
+            Instruction::JIf(Src::Here(-8, Q), Label(0)),
+            Instruction::FFIRet(Src::Here(-16, Q)),
+            Instruction::Label(Label(1)),
+            Instruction::Trap(1),
+        ],
+        data: vec![],
+        globals: vec![],
+    }
+}
+
+// shuffles 64 bytes back and forth a byte at a time, n times, and returns a byte that went along
+fn copies() -> Object {
+    let nowhere = Dest::Nowhere;
+    Object {
+        instructions: vec![
+            Instruction::FFIBegin(144, [Dest::Here(-8, Q), nowhere, nowhere, nowhere, nowhere, nowhere]),
+            Instruction::Copy(Dest::Here(-72, Q), Src::Imm(0x0807_0605_0403_0201), Count(1)),
+            Instruction::Label(Label(0)),
+            Instruction::Copy(Dest::Here(-136, B), Src::Here(-72, B), Count(64)),
+            Instruction::Copy(Dest::Here(-71, B), Src::Here(-136, B), Count(63)),
+            Instruction::SubChecked(Dest::Here(-8, Q), Src::Here(-8, Q), Src::Imm(1), Unsigned, Label(1)),
+            Instruction::JIf(Src::Here(-8, Q), Label(0)),
+            Instruction::Label(Label(1)),
+            Instruction::FFIRet(Src::Here(-70, B)),
+        ],
+        data: vec![],
+        globals: vec![],
+    }
+}
+
+// counts down, switching on the low byte of the count: 0..3 add different amounts, and the rest take the default
+fn switch() -> Object {
+    let nowhere = Dest::Nowhere;
+    let add = |label, amount| [
+        Instruction::Label(Label(label)),
+        Instruction::AddChecked(Dest::Here(-16, Q), Src::Here(-16, Q), Src::Imm(amount), Unsigned, Label(9)),
+        Instruction::JIf(Src::Imm(1), Label(1)),
+    ];
+    let mut instructions = vec![
+        Instruction::FFIBegin(16, [Dest::Here(-8, Q), nowhere, nowhere, nowhere, nowhere, nowhere]),
+        Instruction::Copy(Dest::Here(-16, Q), Src::Imm(0), Count(1)),
+        Instruction::Label(Label(0)),
+        Instruction::Switch(Src::Here(-8, B), Label(6), vec![Label(2), Label(3), Label(4), Label(5)]),
+    ];
+    for (label, amount) in [(2, 1), (3, 10), (4, 100), (5, 1000), (6, 10000)] {
+        instructions.extend(add(label, amount));
+    }
+    instructions.extend([
+        Instruction::Label(Label(1)),
+        Instruction::SubChecked(Dest::Here(-8, Q), Src::Here(-8, Q), Src::Imm(1), Unsigned, Label(9)),
+        Instruction::JIf(Src::Here(-8, Q), Label(0)),
+        Instruction::Label(Label(9)),
+        Instruction::FFIRet(Src::Here(-16, Q)),
+    ]);
+    Object { instructions, data: vec![], globals: vec![] }
+}
+
+// counts a global up with FetchAdd, and tries to CompareExchange arg0 into another one that starts at 0 (only the first try works)
+fn atomics() -> Object {
+    let nowhere = Dest::Nowhere;
+    let (count, first) = (GlobalId(0), GlobalId(1));
+    Object {
+        instructions: vec![
+            Instruction::FFIBegin(24, [Dest::Here(-8, Q), nowhere, nowhere, nowhere, nowhere, nowhere]),
+            Instruction::AtomicStore(Dest::Global(count, 0, Q), Src::Imm(0), Ordering::Relaxed),
+            Instruction::AtomicStore(Dest::Global(first, 0, Q), Src::Imm(0), Ordering::Relaxed),
+            Instruction::Label(Label(0)),
+            Instruction::FetchAdd(Dest::Here(-16, Q), Dest::Global(count, 0, Q), Src::Imm(1), Ordering::SeqCst),
+            Instruction::CompareExchange(Dest::Here(-24, Q), Dest::Global(first, 0, Q), Src::Imm(0), Src::Here(-8, Q), Ordering::AcqRel),
+            Instruction::SubChecked(Dest::Here(-8, Q), Src::Here(-8, Q), Src::Imm(1), Unsigned, Label(1)),
+            Instruction::JIf(Src::Here(-8, Q), Label(0)),
+            Instruction::Label(Label(1)),
+            Instruction::AtomicLoad(Dest::Here(-16, Q), Src::Global(first, 0, Q), Ordering::Acquire),
+            Instruction::FFIRet(Src::Here(-16, Q)),
+        ],
+        data: vec![],
+        globals: vec![
+            Blob { name: "count".to_string(), align: 8, bytes: vec![0; 8] },
+            Blob { name: "first".to_string(), align: 8, bytes: vec![0; 8] },
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the benchmarks only mean something if both engines get the answer the programs are meant to
+    #[test]
+    fn programs() {
+        let answers = |n: u64| [
+            n * (n + 1) / 2,
+            // the first 8 bytes are 1..8, and each time around they get spread one byte further
+            if n == 1 { 2 } else { 1 },
+            (1..=n).map(|i| [1, 10, 100, 1000].get((i & 0xff) as usize).copied().unwrap_or(10000)).sum(),
+            n,
+        ];
+        for n in [1, 2, 7, 300] {
+            for ((name, program), answer) in PROGRAMS.iter().zip(answers(n)) {
+                let interp = InterpreterFn::new(program(), 1024);
+                assert_eq!(interp.run(n, 0, 0, 0, 0, 0).unwrap(), answer, "{} with n = {}", name, n);
+                assert_eq!(interp.compile().unwrap().run(n, 0, 0, 0, 0, 0).unwrap(), answer, "compiled {} with n = {}", name, n);
+            }
+        }
+        assert!(instructions_run(sum_loop(), 10) > 40);
+    }
+}
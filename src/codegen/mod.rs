@@ -1,48 +1,216 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, ops::Range, mem::{offset_of, size_of}};
 
-use crate::instruction::{Instruction, Dest, Src, Size, Label, same_size};
+use crate::instruction::{Instruction, Dest, Src, Size, Label, DataId, GlobalId, Addr, Ordering, Signedness, same_size};
+use crate::object::{Blob, layout};
+use crate::trap::TrapCode;
 
+use self::relax::Branch;
 
-struct LabelReference {
+mod relax;
+
+
+struct Reference {
     at: usize,
-    label: Label,
+    target: Target,
 
     // if relative, then this is 32 bit and well, relative
     // otherwise, it's absolute (64 bit)
     relative_to: Option<usize>
 }
 
+// the smallest a guard page can be
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Clone, Copy)]
+enum Target { Label(Label), Data(DataId), Global(GlobalId, i32), Import(usize), Runtime(usize) }
+
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    // position-independent code: everything is addressed relative to rip
+    // (FFI targets go through an import table), so the base address doesn't matter
+    pub pic: bool,
+
+    // spend a unit of the Runtime's fuel on entry and at every label (so every loop), trapping when it runs out
+    pub fuel: bool,
+
+    // check the Runtime's interrupt flag at the same places, trapping if it's been set
+    pub interruptible: bool,
+
+    // pointers are offsets into the Runtime's linear memory, and are checked against its size before they're used
+    // (trapping if they're out of bounds). JmpIndirect isn't allowed, since it could jump anywhere,
+    // and anything on the stack has to be inside the FFIBegin frame, and anything in a global inside that global
+    pub sandbox: bool,
+
+    // FFIBegin frames bigger than this are an error. None means as big as `sub rsp, imm32` allows
+    pub max_frame_size: Option<u64>,
+}
+
+// something wrong with the IR, found while writing it and reported by `finalize`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CodegenError {
+    FrameTooBig { ir_index: usize, n_bytes: u64, max: u64 },
+    LabelDefinedTwice(Label),
+    LabelNotDefined(Label),
+    DataNotDefined(DataId),
+    GlobalNotDefined(GlobalId),
+    SwitchTooBig { ir_index: usize, n_labels: usize },  // more labels than cmp rax, imm32 can check for
+    // the same mistakes the interpreter calls Problems
+    SizeMismatch { ir_index: usize },
+    BadOrdering { ir_index: usize, ordering: Ordering },
+    NotInMemory { ir_index: usize },
+    // only in a sandbox, where nothing else is checked at runtime
+    OutsideFrame { ir_index: usize, offset: i32, n_bytes: u64 },
+    OutsideGlobal { ir_index: usize, global: GlobalId, offset: i32, n_bytes: u64 },
+    NotInSandbox { ir_index: usize },  // JmpIndirect
+    IndexedStack { ir_index: usize },  // an Addr with an index but no pointer
+}
+
+
+// the code, followed by the data, the globals, (for PIC) the import table and the runtime
+pub struct Image {
+    pub bytes: Vec<u8>,
+    pub read_only: Range<usize>,  // the data's pages, which nothing should write to once it's loaded
+    pub globals: Vec<Range<usize>>,  // where each global is in `bytes`
+    pub runtime: Option<usize>,  // where the Runtime is in `bytes`
+    pub position_independent: bool,
+}
+
+impl From<Vec<u8>> for Image {
+    fn from(bytes: Vec<u8>) -> Self {
+        Image { bytes, read_only: 0..0, globals: vec![], runtime: None, position_independent: false }
+    }
+}
+
+// how the code and JitFn talk to each other
+#[repr(C)]
+pub struct Runtime {
+    // see TrapCode::to_raw
+    pub trap_kind: u64,
+    pub trap_payload: u64,
+    pub trap_index: u64,
+
+    pub fuel: u64,
+    pub interrupt: u64,  // points to a bool that someone else may set while the code runs
+
+    pub memory: u64,  // address of the sandbox's linear memory
+    pub memory_size: u64,
+}
+
 
 pub struct Codegen {
     base_address: u64,
+    options: Options,
     code: Vec<u8>,
 
-    label_references: Vec<LabelReference>,
+    branches: Vec<Branch>,
+    references: Vec<Reference>,
     label_locations: HashMap<Label, usize>,
+    imports: Vec<u64>,  // function addresses for `call [rip + ?]`
+
+    ir_index: usize,  // of the instruction being written
+    frame_size: u64,  // of the last FFIBegin
+    global_accesses: Vec<(usize, GlobalId, i32, u64)>,  // in a sandbox: (ir_index, global, offset, n_bytes), checked by `finalize`
+    error: Option<CodegenError>,  // the first one. the rest of the IR still gets written, but never used
 }
 
 impl Codegen {
     pub fn new(base_address: u64) -> Self {
-        Codegen { base_address, code: vec![], label_references: vec![], label_locations: HashMap::new() }
+        Codegen::with_options(base_address, Options::default())
     }
 
-    pub fn finalize(mut self) -> Vec<u8> {
-        for i in self.label_references {
-            let location = *self.label_locations.get(&i.label).expect("label not defined");
+    pub fn with_options(base_address: u64, options: Options) -> Self {
+        Codegen { 
+            base_address, options, code: vec![], 
+            branches: vec![], references: vec![], label_locations: HashMap::new(), imports: vec![],
+            ir_index: 0, frame_size: 0, global_accesses: vec![], error: None,
+        }
+    }
+
+    // data and globals go after the code, aligned relative to the start of it.
+    // the data gets whole pages to itself, so they can be made read-only
+    pub fn finalize(self, data: &[Blob], globals: &[Blob]) -> Result<Image, CodegenError> {
+        if let Some(error) = self.error { return Err(error) }
+        let used_labels = self.branches.iter().map(|branch| branch.label)
+            .chain(self.references.iter().filter_map(|reference| match reference.target {
+                Target::Label(label) => Some(label),
+                _ => None,
+            }));
+        for label in used_labels {
+            if !self.label_locations.contains_key(&label) { return Err(CodegenError::LabelNotDefined(label)) }
+        }
+        for reference in self.references.iter() {
+            match reference.target {
+                Target::Data(id) if id.0 >= data.len() => return Err(CodegenError::DataNotDefined(id)),
+                Target::Global(id, _) if id.0 >= globals.len() => return Err(CodegenError::GlobalNotDefined(id)),
+                _ => {}
+            }
+        }
+        for (ir_index, global, offset, n_bytes) in self.global_accesses.iter().copied() {
+            if let Some(blob) = globals.get(global.0) {
+                if offset < 0 || offset as u64 + n_bytes > blob.bytes.len() as u64 {
+                    return Err(CodegenError::OutsideGlobal { ir_index, global, offset, n_bytes })
+                }
+            }
+        }
+
+        let locate = |label| *self.label_locations.get(&label).expect("checked above");
+        let (mut code, layout_after_relaxing) = relax::relax(&self.code, &self.branches, locate);
+
+        let read_only = match data.is_empty() {
+            true => code.len()..code.len(),
+            false => {
+                let start = page_align(code.len());
+                start..page_align(layout(start, data).1)
+            }
+        };
+        let (data_locations, _) = layout(read_only.start, data);
+        let (global_locations, end) = layout(read_only.end, globals);
+        let imports_start = end.div_ceil(8) * 8;
+        code.resize(imports_start, 0x00);
+        for (blob, location) in data.iter().zip(data_locations.iter()).chain(globals.iter().zip(global_locations.iter())) {
+            code[*location..*location + blob.bytes.len()].clone_from_slice(&blob.bytes);
+        }
+        for function in self.imports.iter() {
+            code.extend(function.to_le_bytes());
+        }
+        let runtime = code.len();
+        code.resize(runtime + size_of::<Runtime>(), 0x00);
+
+        for i in self.references.iter() {
+            let location = match i.target {
+                Target::Label(label) => layout_after_relaxing.map(locate(label)),
+                Target::Data(DataId(id)) => data_locations[id],
+                Target::Global(GlobalId(id), offset) => 
+                    (global_locations[id] as isize + offset as isize) as usize,
+                Target::Import(index) => imports_start + 8 * index,
+                Target::Runtime(offset) => runtime + offset,
+            };
+            let at = layout_after_relaxing.map(i.at);
 
             if let Some(rel) = i.relative_to {
-                let offset = ((location as isize) - (rel as isize)) as i32;
+                let offset = ((location as isize) - (layout_after_relaxing.map(rel) as isize)) as i32;
                 let bytes: [u8; 4] = offset.to_le_bytes();
-                self.code[i.at..i.at + 4].clone_from_slice(&bytes)
+                code[at..at + 4].clone_from_slice(&bytes)
             } else {
-                let bytes = location.to_le_bytes();
-                self.code[i.at..i.at + 8].clone_from_slice(&bytes);
+                let bytes = (self.base_address + location as u64).to_le_bytes();
+                code[at..at + 8].clone_from_slice(&bytes);
             }
         }
-        return self.code;
+
+        let globals = globals.iter().zip(global_locations)
+            .map(|(blob, location)| location..location + blob.bytes.len())
+            .collect();
+        Ok(Image { bytes: code, read_only, globals, runtime: Some(runtime), position_independent: self.options.pic })
     }
 
     pub fn write(&mut self, instruction: Instruction) {
+        if let Some(error) = self.invalid(&instruction) {
+            self.fail(error);
+            self.ir_index += 1;
+            return
+        }
+
         match instruction {
             Instruction::FFIBegin(n_bytes, args) => {
                 // prologue
@@ -54,11 +222,17 @@ impl Codegen {
                 ]);
 
                 // alloc bytes needed
-                if n_bytes > 0 {
+                let max = self.options.max_frame_size.unwrap_or(i32::MAX as u64).min(i32::MAX as u64);
+                if n_bytes > max {
+                    self.fail(CodegenError::FrameTooBig { ir_index: self.ir_index, n_bytes, max });
+                } else if n_bytes > PAGE_SIZE {
+                    self.probe_stack(n_bytes);
+                } else if n_bytes > 0 {
                     // subtract the number of bytes needed from rsp
                     self.code.extend([0x48, 0x81, 0xec]);
                     self.code.extend((n_bytes as u32).to_le_bytes());
                 }
+                self.frame_size = n_bytes;
 
                 // mov rax, {rdi, rsi, rdx, rcx, r8, r9}
                 let arg0_impl: &[u8] = b"\x48\x89\xf8";
@@ -81,40 +255,36 @@ impl Codegen {
                         self.store_rax(arg);
                     }
                 }
+
+                self.checkpoint();
             }
             Instruction::FFIRet(src) => {
                 // return value is a u64
                 self.load_rax(src);
-
-                // epilogue
-                self.code.extend([
-                    // mov rsp, rbp
-                    0x48, 0x89, 0xec,
-                    // pop rbp
-                    0x5d,
-                    // ret
-                    0xc3,
-                ])
+                self.epilogue();
             }
 
             // TODO: String ops?
             Instruction::Copy(dest, src, count) => {
-                if count.0 != 1 { assert!(same_size(dest, src)); }
                 if dest.needs_store() {
                     for i in 0..count.0 {
-                        self.load_rax(src.offset(i as i32));
-                        self.store_rax(dest.offset(i as i32))
+                        match src {
+                            // no need to go through rax
+                            Src::Imm(x) if Stored::Imm(x).fits(dest) => {
+                                self.store(dest.offset(i as i32), Stored::Imm(x))
+                            }
+                            _ => {
+                                self.load_rax(src.offset(i as i32));
+                                self.store_rax(dest.offset(i as i32))
+                            }
+                        }
                     }
                 }
             }
             Instruction::JIf(Src::Imm(0), _) => { /* generate nothing -- label can't be reached */ },
             Instruction::JIf(Src::Imm(_), label) => { 
                 // jmp
-                self.code.extend([0xe9]);
-                let at = self.code.len();
-                self.code.extend([0x00, 0x00, 0x00, 0x00]);
-                let relative_to = Some(self.code.len());
-                self.label_references.push(LabelReference {at, label, relative_to})
+                self.jump(None, label);
             }
             Instruction::JIf(src, label) => {
                 self.load_rax(src);
@@ -123,26 +293,353 @@ impl Codegen {
                 self.code.extend([0x48, 0x85, 0xc0]);
 
                 // jnz
-                self.code.extend([0x0f, 0x85]);
-                let at = self.code.len();
-                self.code.extend([0x00, 0x00, 0x00, 0x00]);
-                let relative_to = Some(self.code.len());
-                self.label_references.push(LabelReference {at, label, relative_to})
+                self.jump(Some(0x5), label);
+            }
+
+            Instruction::Switch(src, default, labels) => {
+                let n_labels = labels.len() as i32;
+                self.load_rax(src);
+
+                // cmp rax, n_labels
+                self.code.extend([0x48, 0x3d]);
+                self.code.extend(n_labels.to_le_bytes());
+
+                // jae default (unsigned, so negative indices go there too)
+                self.jump(Some(0x3), default);
+
+                if self.options.pic {
+                    // lea rcx, [rip + 9] (the table starts right after the jmp)
+                    self.code.extend([0x48, 0x8d, 0x0d, 0x09, 0x00, 0x00, 0x00]);
+                    // movsxd rax, DWORD PTR [rcx + rax*4]
+                    self.code.extend([0x48, 0x63, 0x04, 0x81]);
+                    // add rax, rcx
+                    self.code.extend([0x48, 0x01, 0xc8]);
+                    // jmp rax
+                    self.code.extend([0xff, 0xe0]);
+
+                    // the table itself: where each label is, relative to the table
+                    let table = self.code.len();
+                    for label in labels {
+                        let at = self.code.len();
+                        self.code.extend([0x00; 4]);
+                        self.references.push(Reference {at, target: Target::Label(label), relative_to: Some(table)})
+                    }
+                } else {
+                    // lea rcx, [rip + 3] (the table starts right after the jmp)
+                    self.code.extend([0x48, 0x8d, 0x0d, 0x03, 0x00, 0x00, 0x00]);
+                    // jmp QWORD PTR [rcx + rax*8]
+                    self.code.extend([0xff, 0x24, 0xc1]);
+
+                    // the table itself: one absolute address per label
+                    for label in labels {
+                        let at = self.code.len();
+                        self.code.extend([0x00; 8]);
+                        self.references.push(Reference {at, target: Target::Label(label), relative_to: None})
+                    }
+                }
+            }
+
+            Instruction::JmpIndirect(src) => {
+                self.load_rax(src);
+                // jmp rax
+                self.code.extend([0xff, 0xe0]);
             }
 
             Instruction::Label(label) => {
                 let existing = self.label_locations.insert(label, self.code.len());
                 if  let Some(_) = existing {
-                    panic!("label defined twice: {:?}", label) // TODO: More graceful way to save the error for later
+                    self.fail(CodegenError::LabelDefinedTwice(label));
                 } 
+
+                // every loop goes through a label, so this is enough to stop them running forever
+                self.checkpoint();
             }
 
             Instruction::FFICall(dest, args, function) => {
-                self.write_fficall(dest, args, function as u64);
+                self.write_fficall(dest, args, function.pointer as u64);
+            }
+
+            // x86 is strong enough that aligned movs are already acquire loads and release stores
+            // only seq_cst stores need anything more (an xchg)
+            Instruction::AtomicLoad(dest, src, _) => {
+                self.load_rax(src);
+                self.store_rax(dest);
+            }
+            Instruction::AtomicStore(place, src, ordering) => {
+                self.load_rax(src);
+                let sz = self.lea_rcx(place);
+                if ordering == Ordering::SeqCst {
+                    self.xchg_rcx(sz);
+                } else {
+                    self.store_relative_to_rcx(0, sz);
+                }
+            }
+            Instruction::FetchAdd(old, place, src, _) => {
+                self.load_rax(src);
+                let sz = self.lea_rcx(place);
+                match sz {
+                    // lock xadd [rcx], al
+                    Size::B => self.code.extend([0xf0, 0x0f, 0xc0, 0x01]),
+                    // lock xadd [rcx], ax
+                    Size::H => self.code.extend([0x66, 0xf0, 0x0f, 0xc1, 0x01]),
+                    // lock xadd [rcx], eax
+                    Size::D => self.code.extend([0xf0, 0x0f, 0xc1, 0x01]),
+                    // lock xadd [rcx], rax
+                    Size::Q => self.code.extend([0xf0, 0x48, 0x0f, 0xc1, 0x01]),
+                }
+                self.zero_extend_rax(sz);
+                self.store_rax(old);
+            }
+            Instruction::Swap(old, place, src, _) => {
+                self.load_rax(src);
+                let sz = self.lea_rcx(place);
+                self.xchg_rcx(sz);
+                self.zero_extend_rax(sz);
+                self.store_rax(old);
+            }
+            Instruction::CompareExchange(old, place, expected, new, _) => {
+                // cmpxchg wants expected in rax, so new goes in rdx (by way of the stack)
+                self.load_rax(new);
+                // push rax
+                self.code.push(0x50);
+                self.load_rax(expected);
+                // push rax
+                self.code.push(0x50);
+                let sz = self.lea_rcx(place);
+                // pop rax;  pop rdx
+                self.code.extend([0x58, 0x5a]);
+                match sz {
+                    // lock cmpxchg [rcx], dl
+                    Size::B => self.code.extend([0xf0, 0x0f, 0xb0, 0x11]),
+                    // lock cmpxchg [rcx], dx
+                    Size::H => self.code.extend([0x66, 0xf0, 0x0f, 0xb1, 0x11]),
+                    // lock cmpxchg [rcx], edx
+                    Size::D => self.code.extend([0xf0, 0x0f, 0xb1, 0x11]),
+                    // lock cmpxchg [rcx], rdx
+                    Size::Q => self.code.extend([0xf0, 0x48, 0x0f, 0xb1, 0x11]),
+                }
+                // either way, rax now holds what was there before
+                self.zero_extend_rax(sz);
+                self.store_rax(old);
+            }
+            Instruction::Fence(ordering) => {
+                if ordering == Ordering::SeqCst {
+                    // mfence
+                    self.code.extend([0x0f, 0xae, 0xf0]);
+                }
+            }
+
+            Instruction::AddChecked(dest, a, b, signedness, overflow) => {
+                match b {
+                    Src::Imm(x) if fits_in_i32(x) => {
+                        self.load_rax(a);
+                        // add rax, ...
+                        self.code.extend([0x48, 0x05]);
+                        self.code.extend((x as u32).to_le_bytes());
+                    }
+                    _ => {
+                        self.load_rax_and_rcx(a, b);
+                        // add rax, rcx
+                        self.code.extend([0x48, 0x01, 0xc8]);
+                    }
+                }
+                self.jump_on_overflow(signedness, overflow);
+                self.store_rax(dest);
+            }
+            Instruction::SubChecked(dest, a, b, signedness, overflow) => {
+                match b {
+                    Src::Imm(x) if fits_in_i32(x) => {
+                        self.load_rax(a);
+                        // sub rax, ...
+                        self.code.extend([0x48, 0x2d]);
+                        self.code.extend((x as u32).to_le_bytes());
+                    }
+                    _ => {
+                        self.load_rax_and_rcx(a, b);
+                        // sub rax, rcx
+                        self.code.extend([0x48, 0x29, 0xc8]);
+                    }
+                }
+                self.jump_on_overflow(signedness, overflow);
+                self.store_rax(dest);
             }
+            Instruction::MulChecked(dest, a, b, Signedness::Signed, overflow) => {
+                match b {
+                    Src::Imm(x) if fits_in_i32(x) => {
+                        self.load_rax(a);
+                        // imul rax, rax, ...
+                        self.code.extend([0x48, 0x69, 0xc0]);
+                        self.code.extend((x as u32).to_le_bytes());
+                    }
+                    _ => {
+                        self.load_rax_and_rcx(a, b);
+                        // imul rax, rcx
+                        self.code.extend([0x48, 0x0f, 0xaf, 0xc1]);
+                    }
+                }
+                // jo
+                self.jump(Some(0x0), overflow);
+                self.store_rax(dest);
+            }
+            Instruction::MulChecked(dest, a, b, Signedness::Unsigned, overflow) => {
+                // (there's no unsigned multiply by an immediate)
+                self.load_rax_and_rcx(a, b);
+                // mul rcx (clobbers rdx, and sets CF if anything ended up there)
+                self.code.extend([0x48, 0xf7, 0xe1]);
+                // jc
+                self.jump(Some(0x2), overflow);
+                self.store_rax(dest);
+            }
+
+            Instruction::Trap(code) => {
+                self.trap(TrapCode::User(code));
+            }
+        }
+
+        self.ir_index += 1;
+    }
+
+    fn epilogue(&mut self) {
+        self.code.extend([
+            // mov rsp, rbp
+            0x48, 0x89, 0xec,
+            // pop rbp
+            0x5d,
+            // ret
+            0xc3,
+        ])
+    }
+
+    fn fail(&mut self, error: CodegenError) {
+        self.error.get_or_insert(error);
+    }
+
+    // what's wrong with an instruction that can't be written at all
+    fn invalid(&self, instruction: &Instruction) -> Option<CodegenError> {
+        let ir_index = self.ir_index;
+        let bad_ordering = |valid: bool, ordering| if valid { None } else { Some(CodegenError::BadOrdering { ir_index, ordering }) };
+        let in_memory = |in_memory: bool| if in_memory { None } else { Some(CodegenError::NotInMemory { ir_index }) };
+        match instruction {
+            Instruction::Copy(dest, src, count) if count.0 != 1 && !same_size(*dest, *src) => Some(CodegenError::SizeMismatch { ir_index }),
+            Instruction::Switch(_, _, labels) if i32::try_from(labels.len()).is_err() => Some(CodegenError::SwitchTooBig { ir_index, n_labels: labels.len() }),
+            Instruction::JmpIndirect(_) if self.options.sandbox => Some(CodegenError::NotInSandbox { ir_index }),
+            Instruction::AtomicLoad(_, src, ordering) => bad_ordering(ordering.valid_for_load(), *ordering).or(in_memory(src.needs_load())),
+            Instruction::AtomicStore(place, _, ordering) => bad_ordering(ordering.valid_for_store(), *ordering).or(in_memory(place.needs_store())),
+            Instruction::FetchAdd(_, place, ..) | Instruction::Swap(_, place, ..) | Instruction::CompareExchange(_, place, ..) => in_memory(place.needs_store()),
+            Instruction::Fence(ordering) => bad_ordering(ordering.valid_for_fence(), *ordering),
+            _ => None,
+        }
+    }
+
+    // moves rsp down a page at a time, touching each one, so the guard page can't be skipped over
+    fn probe_stack(&mut self, n_bytes: u64) {
+        // mov r11, n_pages
+        self.code.extend([0x49, 0xc7, 0xc3]);
+        self.code.extend(((n_bytes / PAGE_SIZE) as u32).to_le_bytes());
+
+        // sub rsp, PAGE_SIZE
+        self.code.extend([0x48, 0x81, 0xec]);
+        self.code.extend((PAGE_SIZE as u32).to_le_bytes());
+        // test QWORD PTR [rsp], rsp
+        self.code.extend([0x48, 0x85, 0x24, 0x24]);
+        // dec r11
+        self.code.extend([0x49, 0xff, 0xcb]);
+        // jnz (back to the sub)
+        self.code.extend([0x75, 0xf0]);
+
+        // what's left is less than a page
+        if !n_bytes.is_multiple_of(PAGE_SIZE) {
+            // sub rsp, ...
+            self.code.extend([0x48, 0x81, 0xec]);
+            self.code.extend(((n_bytes % PAGE_SIZE) as u32).to_le_bytes());
+        }
+    }
+
+    // where the code may be stopped from outside (clobbers rax).
+    // it comes at the end of an instruction, so the trap is at the next one, which didn't run -- the same as the interpreter
+    fn checkpoint(&mut self) {
+        let next = self.ir_index + 1;
+        if self.options.fuel {
+            // sub QWORD PTR [rip + ?], 1
+            self.code.extend([0x48, 0x83, 0x2d]);
+            self.rip_relative_then(Target::Runtime(offset_of!(Runtime, fuel)), &[0x01]);
+
+            // jae (it didn't go below 0)
+            self.unless(0x3, |codegen| codegen.trap_at(TrapCode::OutOfFuel, next));
+        }
+
+        if self.options.interruptible {
+            // mov rax, QWORD PTR [rip + ?]
+            self.code.extend([0x48, 0x8b, 0x05]);
+            self.rip_relative(Target::Runtime(offset_of!(Runtime, interrupt)));
+            // cmp BYTE PTR [rax], 0
+            self.code.extend([0x80, 0x38, 0x00]);
+
+            // je
+            self.unless(0x4, |codegen| codegen.trap_at(TrapCode::Interrupted, next));
         }
     }
 
+    // jcc over whatever `body` writes, which has to be short and can't contain any jumps of its own
+    fn unless(&mut self, condition: u8, body: impl FnOnce(&mut Self)) {
+        self.code.extend([0x70 | condition, 0x00]);
+        let start = self.code.len();
+        body(self);
+        self.code[start - 1] = i8::try_from(self.code.len() - start).expect("too long to skip") as u8;
+    }
+
+    // tells JitFn what happened through the Runtime, then returns
+    fn trap(&mut self, code: TrapCode) {
+        self.trap_at(code, self.ir_index);
+    }
+
+    fn trap_at(&mut self, code: TrapCode, ir_index: usize) {
+        let (kind, payload) = code.to_raw();
+
+        // mov QWORD PTR [rip + ?], kind
+        self.code.extend([0x48, 0xc7, 0x05]);
+        self.rip_relative_then(Target::Runtime(offset_of!(Runtime, trap_kind)), &(kind as u32).to_le_bytes());
+
+        // mov QWORD PTR [rip + ?], payload
+        self.load_rax(Src::Imm(payload));
+        self.code.extend([0x48, 0x89, 0x05]);
+        self.rip_relative(Target::Runtime(offset_of!(Runtime, trap_payload)));
+
+        // mov QWORD PTR [rip + ?], ir_index
+        self.code.extend([0x48, 0xc7, 0x05]);
+        self.rip_relative_then(Target::Runtime(offset_of!(Runtime, trap_index)), &(ir_index as u32).to_le_bytes());
+
+        self.epilogue();
+    }
+
+    // rax = a, rcx = b
+    // loading either can clobber rcx, so b waits on the stack
+    fn load_rax_and_rcx(&mut self, a: Src, b: Src) {
+        self.load_rax(b);
+        // push rax
+        self.code.push(0x50);
+        self.load_rax(a);
+        // pop rcx
+        self.code.push(0x59);
+    }
+
+    // after an add or sub: signed overflow sets OF, unsigned overflow sets CF
+    fn jump_on_overflow(&mut self, signedness: Signedness, label: Label) {
+        match signedness {
+            // jo
+            Signedness::Signed => self.jump(Some(0x0), label),
+            // jc
+            Signedness::Unsigned => self.jump(Some(0x2), label),
+        }
+    }
+
+    // jmp or jcc, whichever encoding is shortest (decided in `finalize`)
+    fn jump(&mut self, condition: Option<u8>, label: Label) {
+        let branch = Branch { at: self.code.len(), label, condition };
+        self.code.resize(self.code.len() + branch.long_len(), 0x00);
+        self.branches.push(branch);
+    }
+
     fn write_fficall(&mut self, dest: Dest, args: [Src; 6], function: u64) {
         // push rdi;   mov rdi, rax
         let arg0_impl: &[u8] = b"\x57\x48\x89\xc7";
@@ -176,12 +673,22 @@ impl Codegen {
             }
         }
 
-        // mov rax, <address of function>
-        self.code.extend([0x48, 0xb8]);
-        self.code.extend(function.to_le_bytes());
+        if self.options.pic {
+            let index = match self.imports.iter().position(|f| *f == function) {
+                Some(index) => index,
+                None => { self.imports.push(function); self.imports.len() - 1 }
+            };
+            // call QWORD PTR [rip + ?]
+            self.code.extend([0xff, 0x15]);
+            self.rip_relative(Target::Import(index));
+        } else {
+            // mov rax, <address of function>
+            self.code.extend([0x48, 0xb8]);
+            self.code.extend(function.to_le_bytes());
 
-        // call rax
-        self.code.extend([0xff, 0xd0]);
+            // call rax
+            self.code.extend([0xff, 0xd0]);
+        }
 
         // mov dest, rax
         if dest.needs_store() {
@@ -228,25 +735,129 @@ impl Codegen {
                 // also clears the top 32 bits
                 self.code.extend([0x31, 0xc0])
             }
+            Src::Imm(x) if x <= u32::MAX as u64 => {
+                // mov eax, ...
+                // also clears the top 32 bits
+                self.code.push(0xb8);
+                self.code.extend((x as u32).to_le_bytes());
+            }
+            Src::Imm(x) if fits_in_i32(x) => {
+                // mov rax, ... (sign-extended)
+                self.code.extend([0x48, 0xc7, 0xc0]);
+                self.code.extend((x as u32).to_le_bytes());
+            }
             Src::Imm(x) => {
-                // TODO: Special case < u32 max value as mov eax instead
-
                 // mov rax, ...
                 self.code.extend([0x48, 0xb8]);
                 self.code.extend((x as u64).to_le_bytes());
             }
+            Src::LabelAddr(label) if self.options.pic => {
+                // lea rax, [rip + ?]
+                self.code.extend([0x48, 0x8d, 0x05]);
+                self.rip_relative(Target::Label(label));
+            }
+            Src::LabelAddr(label) => {
+                // mov rax, <address of label>
+                self.code.extend([0x48, 0xb8]);
+                let at = self.code.len();
+                self.code.extend([0x00; 8]);
+                self.references.push(Reference {at, target: Target::Label(label), relative_to: None})
+            }
+            Src::DataAddr(data) => {
+                // lea rax, [rip + ?]
+                self.code.extend([0x48, 0x8d, 0x05]);
+                self.rip_relative(Target::Data(data));
+            }
+            Src::AddrOf(stack_offset) => {
+                // lea rax, [rbp + ?]
+                self.code.extend([0x48, 0x8d, 0x85]);
+                self.code.extend(stack_offset.to_le_bytes());
+            }
             Src::Ptr(offset_to_ptr, offset_after_ptr, sz) => {
-                // mov rax, rbp
-                self.code.extend([0x48, 0x89, 0xe8]);
-                self.load_relative_to_rax(offset_to_ptr, Size::Q);
-                // now the pointer is in rax
-                self.load_relative_to_rax(offset_after_ptr, sz)
+                self.load_rax_at(Addr::ptr(offset_to_ptr, offset_after_ptr), sz)
             }
             Src::Here(stack_offset, sz) => {
+                self.check_in_frame(stack_offset, sz);
                 // mov rax, rbp
                 self.code.extend([0x48, 0x89, 0xe8]);
                 self.load_relative_to_rax(stack_offset, sz)
             }
+            Src::At(addr, sz) => {
+                self.load_rax_at(addr, sz)
+            }
+            Src::Global(global, offset, sz) => {
+                self.check_in_global(global, offset, sz);
+                match sz {
+                    // movzx eax, BYTE PTR [rip + ?]
+                    Size::B => self.code.extend([0x0f, 0xb6, 0x05]),
+                    // movzx eax, WORD PTR [rip + ?]
+                    Size::H => self.code.extend([0x0f, 0xb7, 0x05]),
+                    // mov eax, DWORD PTR [rip + ?]
+                    Size::D => self.code.extend([0x8b, 0x05]),
+                    // mov rax, QWORD PTR [rip + ?]
+                    Size::Q => self.code.extend([0x48, 0x8b, 0x05]),
+                }
+                self.rip_relative(Target::Global(global, offset));
+            }
+        }
+    }
+
+    // the 32-bit displacement at the end of a rip-relative instruction
+    fn rip_relative(&mut self, target: Target) {
+        self.rip_relative_then(target, &[])
+    }
+
+    // ... or not quite at the end, if there's an immediate after it
+    // (rip is the address of the next instruction, so the immediate counts)
+    fn rip_relative_then(&mut self, target: Target, immediate: &[u8]) {
+        let at = self.code.len();
+        self.code.extend([0x00; 4]);
+        self.code.extend(immediate);
+        let relative_to = Some(self.code.len());
+        self.references.push(Reference {at, target, relative_to})
+    }
+
+    fn load_rax_at(&mut self, addr: Addr, sz: Size) {
+        if self.sandboxed(addr, sz) {
+            self.linear_address_to_r10(addr, sz);
+            match sz {
+                // movzx eax, BYTE PTR [r10]
+                Size::B => self.code.extend([0x41, 0x0f, 0xb6, 0x02]),
+                // movzx eax, WORD PTR [r10]
+                Size::H => self.code.extend([0x41, 0x0f, 0xb7, 0x02]),
+                // mov eax, DWORD PTR [r10]
+                Size::D => self.code.extend([0x41, 0x8b, 0x02]),
+                // mov rax, QWORD PTR [r10]
+                Size::Q => self.code.extend([0x49, 0x8b, 0x02]),
+            }
+            return
+        }
+
+        // mov rax, rbp
+        self.code.extend([0x48, 0x89, 0xe8]);
+        for offset in addr.chain.offsets() {
+            self.load_relative_to_rax(*offset, Size::Q);
+        }
+        // now the base is in rax
+
+        match addr.index {
+            None => self.load_relative_to_rax(addr.disp, sz),
+            Some((index_offset, scale)) => {
+                self.index_to_r11(index_offset);
+                match sz {
+                    // movzx eax, BYTE PTR [rax + r11 * scale + ?]
+                    Size::B => self.code.extend([0x42, 0x0f, 0xb6, 0x84]),
+                    // movzx eax, WORD PTR [rax + r11 * scale + ?]
+                    Size::H => self.code.extend([0x42, 0x0f, 0xb7, 0x84]),
+                    // mov eax, DWORD PTR [rax + r11 * scale + ?]
+                    Size::D => self.code.extend([0x42, 0x8b, 0x84]),
+                    // mov rax, QWORD PTR [rax + r11 * scale + ?]
+                    Size::Q => self.code.extend([0x4a, 0x8b, 0x84]),
+                }
+                // SIB: index r11, base rax
+                self.code.push(sib(scale, 0b011, 0b000));
+                self.code.extend(addr.disp.to_le_bytes());
+            }
         }
     }
 
@@ -262,6 +873,7 @@ impl Codegen {
                 // mov rax, QWORD PTR [rax]
                 Size::Q => self.code.extend([0x48, 0x8b, 0x00]),
             }
+            return
         }
         match sz {
             // movzx eax, BYTE PTR [rax + ?]
@@ -278,23 +890,229 @@ impl Codegen {
     
 
     fn store_rax(&mut self, dest: Dest) {
-        match dest {
-            Dest::Nowhere => { /* do nothing! */ }
-            Dest::Ptr(offset_to_ptr, offset_after_ptr, sz) => {
-                // mov rax, rbp
-                self.code.extend([0x48, 0x89, 0xe8]);
-                self.load_relative_to_rax(offset_to_ptr, Size::Q);
-                // mov rcx, rax
-                self.code.extend([0x48, 0x89, 0xc1]);
-                // mov [rcx + sz], rax
-                self.store_relative_to_rcx(offset_after_ptr, sz)
+        self.store(dest, Stored::Rax)
+    }
+
+    // the address is worked out in r10 (and r11 for the index), so rax and the argument registers are left alone
+    fn store(&mut self, dest: Dest, stored: Stored) {
+        let (addr, sz) = match dest {
+            Dest::Nowhere => { /* do nothing! */ return }
+            Dest::Here(stack_offset, sz) => {
+                self.check_in_frame(stack_offset, sz);
+                // mov [rbp + ?], ...
+                self.code.extend(stored.opcode(sz, 0));
+                self.code.push(0x85);
+                self.code.extend(stack_offset.to_le_bytes());
+                self.code.extend(stored.immediate(sz));
+                return
+            }
+            Dest::Global(global, offset, sz) => {
+                self.check_in_global(global, offset, sz);
+                // mov [rip + ?], ...
+                self.code.extend(stored.opcode(sz, 0));
+                self.code.push(0x05);
+                self.rip_relative_then(Target::Global(global, offset), &stored.immediate(sz));
+                return
+            }
+            Dest::Ptr(offset_to_ptr, offset_after_ptr, sz) => (Addr::ptr(offset_to_ptr, offset_after_ptr), sz),
+            Dest::At(addr, sz) => (addr, sz),
+        };
+
+        if self.sandboxed(addr, sz) {
+            self.linear_address_to_r10(addr, sz);
+            // mov [r10], ...
+            self.code.extend(stored.opcode(sz, REX_B));
+            self.code.push(0x02);
+            self.code.extend(stored.immediate(sz));
+            return
+        }
+
+        self.base_to_r10(addr);
+        match addr.index {
+            None => {
+                // mov [r10 + ?], ...
+                self.code.extend(stored.opcode(sz, REX_B));
+                self.code.push(0x82);
+            }
+            Some((index_offset, scale)) => {
+                self.index_to_r11(index_offset);
+                // mov [r10 + r11 * scale + ?], ...
+                self.code.extend(stored.opcode(sz, REX_X | REX_B));
+                self.code.extend([0x84, sib(scale, 0b011, 0b010)]);
+            }
+        }
+        self.code.extend(addr.disp.to_le_bytes());
+        self.code.extend(stored.immediate(sz));
+    }
+
+    // r10 and r11 aren't used to pass arguments, so FFIBegin and FFICall can work out addresses
+    // while the argument registers are full
+    fn base_to_r10(&mut self, addr: Addr) {
+        // mov r10, rbp
+        self.code.extend([0x49, 0x89, 0xea]);
+        for offset in addr.chain.offsets() {
+            // mov r10, QWORD PTR [r10 + ?]
+            self.code.extend([0x4d, 0x8b, 0x92]);
+            self.code.extend(offset.to_le_bytes());
+        }
+    }
+
+    // whether addr goes through the linear memory. anything without a pointer in it is on the stack,
+    // where it has to be inside the frame
+    fn sandboxed(&mut self, addr: Addr, sz: Size) -> bool {
+        if !self.options.sandbox { return false }
+        if addr.chain.offsets().is_empty() {
+            if addr.index.is_some() {
+                self.fail(CodegenError::IndexedStack { ir_index: self.ir_index });
+            }
+            self.check_in_frame(addr.disp, sz);
+            return false
+        }
+        true
+    }
+
+    // in a sandbox, [rbp + offset] has to be in [rbp - frame_size, rbp)
+    fn check_in_frame(&mut self, offset: i32, sz: Size) {
+        let n_bytes = sz.n_bytes();
+        if self.options.sandbox && ((offset as i64) < -(self.frame_size as i64) || offset as i64 + n_bytes as i64 > 0) {
+            self.fail(CodegenError::OutsideFrame { ir_index: self.ir_index, offset, n_bytes });
+        }
+    }
+
+    // ... and offset + sz has to fit in the global, which `finalize` knows the size of
+    fn check_in_global(&mut self, global: GlobalId, offset: i32, sz: Size) {
+        if self.options.sandbox {
+            self.global_accesses.push((self.ir_index, global, offset, sz.n_bytes()));
+        }
+    }
+
+    // like base_to_r10, but every pointer is checked and offset by the start of the linear memory,
+    // and it ends up with the address of the place itself (index and displacement included)
+    fn linear_address_to_r10(&mut self, addr: Addr, sz: Size) {
+        let (first, rest) = addr.chain.offsets().split_first().expect("checked by sandboxed");
+        self.check_in_frame(*first, Size::Q);
+
+        // mov r10, QWORD PTR [rbp + ?]
+        self.code.extend([0x4c, 0x8b, 0x95]);
+        self.code.extend(first.to_le_bytes());
+        for offset in rest {
+            // lea r10, [r10 + ?]
+            self.code.extend([0x4d, 0x8d, 0x92]);
+            self.code.extend(offset.to_le_bytes());
+            self.check_bounds_of_r10(Size::Q);
+            // mov r10, QWORD PTR [r10]
+            self.code.extend([0x4d, 0x8b, 0x12]);
+        }
+
+        match addr.index {
+            None => {
+                // lea r10, [r10 + ?]
+                self.code.extend([0x4d, 0x8d, 0x92]);
+            }
+            Some((index_offset, scale)) => {
+                self.check_in_frame(index_offset, Size::Q);
+                self.index_to_r11(index_offset);
+                // lea r10, [r10 + r11 * scale + ?]
+                self.code.extend([0x4f, 0x8d, 0x94, sib(scale, 0b011, 0b010)]);
             }
+        }
+        self.code.extend(addr.disp.to_le_bytes());
+        self.check_bounds_of_r10(sz);
+    }
+
+    // traps unless the sz bytes at offset r10 are all inside the linear memory, then turns r10 into a real address
+    // (clobbers r11)
+    fn check_bounds_of_r10(&mut self, sz: Size) {
+        let n_bytes = sz.n_bytes() as u8;
+
+        // lea r11, [r10 + n_bytes]
+        self.code.extend([0x4d, 0x8d, 0x5a, n_bytes]);
+        // cmp r11, n_bytes
+        self.code.extend([0x49, 0x83, 0xfb, n_bytes]);
+        // jb (it wrapped around) to the trap, past the next cmp and jbe
+        self.code.extend([0x72, 0x09]);
+        // cmp r11, QWORD PTR [rip + ?]
+        self.code.extend([0x4c, 0x3b, 0x1d]);
+        self.rip_relative(Target::Runtime(offset_of!(Runtime, memory_size)));
+        // jbe
+        self.unless(0x6, |codegen| codegen.trap(TrapCode::OutOfBounds));
+
+        // add r10, QWORD PTR [rip + ?]
+        self.code.extend([0x4c, 0x03, 0x15]);
+        self.rip_relative(Target::Runtime(offset_of!(Runtime, memory)));
+    }
+
+    fn index_to_r11(&mut self, index_offset: i32) {
+        // mov r11, QWORD PTR [rbp + ?]
+        self.code.extend([0x4c, 0x8b, 0x9d]);
+        self.code.extend(index_offset.to_le_bytes());
+    }
+
+    // puts the address of a memory operand in rcx without touching rax
+    fn lea_rcx(&mut self, dest: Dest) -> Size {
+        let (addr, sz) = match dest {
+            Dest::Nowhere => unreachable!("checked by invalid"),
             Dest::Here(stack_offset, sz) => {
-                // move rcx, rbp
-                self.code.extend([0x48, 0x89, 0xe9]);
-                // mov [rcx + sz], rax
-                self.store_relative_to_rcx(stack_offset, sz)
+                self.check_in_frame(stack_offset, sz);
+                // lea rcx, [rbp + ?]
+                self.code.extend([0x48, 0x8d, 0x8d]);
+                self.code.extend(stack_offset.to_le_bytes());
+                return sz
+            }
+            Dest::Global(global, offset, sz) => {
+                self.check_in_global(global, offset, sz);
+                // lea rcx, [rip + ?]
+                self.code.extend([0x48, 0x8d, 0x0d]);
+                self.rip_relative(Target::Global(global, offset));
+                return sz
+            }
+            Dest::Ptr(offset_to_ptr, offset_after_ptr, sz) => (Addr::ptr(offset_to_ptr, offset_after_ptr), sz),
+            Dest::At(addr, sz) => (addr, sz),
+        };
+
+        if self.sandboxed(addr, sz) {
+            self.linear_address_to_r10(addr, sz);
+            // mov rcx, r10
+            self.code.extend([0x4c, 0x89, 0xd1]);
+            return sz
+        }
+
+        self.base_to_r10(addr);
+        match addr.index {
+            None => {
+                // lea rcx, [r10 + ?]
+                self.code.extend([0x49, 0x8d, 0x8a]);
             }
+            Some((index_offset, scale)) => {
+                self.index_to_r11(index_offset);
+                // lea rcx, [r10 + r11 * scale + ?]
+                self.code.extend([0x4b, 0x8d, 0x8c, sib(scale, 0b011, 0b010)]);
+            }
+        }
+        self.code.extend(addr.disp.to_le_bytes());
+        sz
+    }
+
+    // after an atomic op on part of rax, the rest of it still has whatever was there before
+    fn zero_extend_rax(&mut self, sz: Size) {
+        match sz {
+            // movzx eax, al
+            Size::B => self.code.extend([0x0f, 0xb6, 0xc0]),
+            // movzx eax, ax
+            Size::H => self.code.extend([0x0f, 0xb7, 0xc0]),
+            // mov eax, eax
+            Size::D => self.code.extend([0x89, 0xc0]),
+            Size::Q => {}
+        }
+    }
+
+    // xchg [rcx], rax (always locked, even without the prefix)
+    fn xchg_rcx(&mut self, sz: Size) {
+        match sz {
+            Size::B => self.code.extend([0x86, 0x01]),
+            Size::H => self.code.extend([0x66, 0x87, 0x01]),
+            Size::D => self.code.extend([0x87, 0x01]),
+            Size::Q => self.code.extend([0x48, 0x87, 0x01]),
         }
     }
 
@@ -311,4 +1129,463 @@ impl Codegen {
         }
         self.code.extend(offset.to_le_bytes())
     }
-}
\ No newline at end of file
+}
+
+// what a store writes: rax, or an immediate that's part of the instruction
+#[derive(Clone, Copy)]
+enum Stored { Rax, Imm(u64) }
+
+impl Stored {
+    // mov r/m, r and mov r/m, imm use the same ModRM byte (the reg field is 0 for both)
+    // so only the opcode is different. `rex` has the X and B bits for an address in r8-r15
+    fn opcode(self, sz: Size, rex: u8) -> Vec<u8> {
+        let mut bytes = vec![];
+        if sz == Size::H { bytes.push(0x66) }
+        let w = if sz == Size::Q { REX_W } else { 0 };
+        if w | rex != 0 { bytes.push(0x40 | w | rex) }
+        bytes.push(match (self, sz) {
+            (Stored::Rax, Size::B) => 0x88,
+            (Stored::Rax, _) => 0x89,
+            (Stored::Imm(_), Size::B) => 0xc6,
+            (Stored::Imm(_), _) => 0xc7,
+        });
+        bytes
+    }
+
+    // goes after the displacement. a QWORD store only gets 32 bits, which are sign-extended
+    fn immediate(self, sz: Size) -> Vec<u8> {
+        match self {
+            Stored::Rax => vec![],
+            Stored::Imm(x) => x.to_le_bytes()[..sz.n_bytes().min(4) as usize].to_vec(),
+        }
+    }
+
+    fn fits(self, dest: Dest) -> bool {
+        match (self, dest.size()) {
+            (Stored::Imm(x), Some(Size::Q)) => fits_in_i32(x),
+            _ => true,
+        }
+    }
+}
+
+const REX_W: u8 = 0x08;
+const REX_X: u8 = 0x02;
+const REX_B: u8 = 0x01;
+
+fn page_align(n: usize) -> usize {
+    let page_size = PAGE_SIZE as usize;
+    n.div_ceil(page_size) * page_size
+}
+
+// whether sign-extending the low 32 bits gets x back
+fn fits_in_i32(x: u64) -> bool {
+    x as i64 == x as i32 as i64
+}
+
+// scale-index-base byte, scaling the index by the size of an element
+fn sib(scale: Size, index: u8, base: u8) -> u8 {
+    let ss = match scale {
+        Size::B => 0b00,
+        Size::H => 0b01,
+        Size::D => 0b10,
+        Size::Q => 0b11,
+    };
+    (ss << 6) | (index << 3) | base
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::{Count, Chain, FFIFunction};
+    use crate::object::Object;
+    use crate::jit_fn::JitFn;
+    use crate::interpreter_fn::InterpreterFn;
+    use crate::trap::Trap;
+
+    const N: Dest = Dest::Nowhere;
+
+    fn sandboxed(instructions: Vec<Instruction>, globals: Vec<Blob>) -> Result<Image, CodegenError> {
+        let object = Object { instructions, data: vec![], globals };
+        object.codegen_with(0, Options { sandbox: true, ..Options::default() })
+    }
+
+    fn copy_then_ret(dest: Dest, src: Src) -> Vec<Instruction> {
+        vec![
+            Instruction::FFIBegin(16, [Dest::Here(-8, Size::Q), N, N, N, N, N]),
+            Instruction::Copy(dest, src, Count(1)),
+            Instruction::FFIRet(Src::Imm(0)),
+        ]
+    }
+
+    #[test]
+    fn sandbox_stays_in_the_frame() {
+        assert!(sandboxed(copy_then_ret(Dest::Here(-16, Size::Q), Src::Here(-8, Size::Q)), vec![]).is_ok());
+
+        let below = copy_then_ret(Dest::Here(-24, Size::Q), Src::Imm(0));
+        assert_eq!(sandboxed(below.clone(), vec![]).err(), Some(CodegenError::OutsideFrame { ir_index: 1, offset: -24, n_bytes: 8 }));
+        // (outside a sandbox, that's fine)
+        assert!(Object { instructions: below, data: vec![], globals: vec![] }.codegen(0).is_ok());
+
+        // the return address and saved rbp are above the frame
+        let above = copy_then_ret(Dest::Here(-16, Size::Q), Src::Here(-4, Size::Q));
+        assert_eq!(sandboxed(above, vec![]).err(), Some(CodegenError::OutsideFrame { ir_index: 1, offset: -4, n_bytes: 8 }));
+        let saved_rbp = copy_then_ret(Dest::Here(-16, Size::Q), Src::Here(0, Size::B));
+        assert_eq!(sandboxed(saved_rbp, vec![]).err(), Some(CodegenError::OutsideFrame { ir_index: 1, offset: 0, n_bytes: 1 }));
+
+        // an Addr without a pointer in it is on the stack too, and so is the pointer at the start of one that has
+        let at = Addr { chain: Chain::new(&[]), index: None, disp: 8 };
+        assert_eq!(sandboxed(copy_then_ret(Dest::At(at, Size::B), Src::Imm(0)), vec![]).err(),
+            Some(CodegenError::OutsideFrame { ir_index: 1, offset: 8, n_bytes: 1 }));
+        assert_eq!(sandboxed(copy_then_ret(Dest::Here(-16, Size::Q), Src::Ptr(16, 0, Size::Q)), vec![]).err(),
+            Some(CodegenError::OutsideFrame { ir_index: 1, offset: 16, n_bytes: 8 }));
+        assert!(sandboxed(copy_then_ret(Dest::Here(-16, Size::Q), Src::Ptr(-8, 1000, Size::Q)), vec![]).is_ok());
+    }
+
+    #[test]
+    fn sandbox_stays_in_globals() {
+        let global = || vec![Blob { name: "g".to_string(), align: 8, bytes: vec![0; 8] }];
+        let g = GlobalId(0);
+        assert!(sandboxed(copy_then_ret(Dest::Global(g, 4, Size::D), Src::Global(g, 0, Size::Q)), global()).is_ok());
+
+        assert_eq!(sandboxed(copy_then_ret(Dest::Global(g, 4, Size::Q), Src::Imm(0)), global()).err(),
+            Some(CodegenError::OutsideGlobal { ir_index: 1, global: g, offset: 4, n_bytes: 8 }));
+        assert_eq!(sandboxed(copy_then_ret(Dest::Here(-16, Size::Q), Src::Global(g, -1, Size::B)), global()).err(),
+            Some(CodegenError::OutsideGlobal { ir_index: 1, global: g, offset: -1, n_bytes: 1 }));
+        let fetch_add = vec![
+            Instruction::FFIBegin(16, [N; 6]),
+            Instruction::FetchAdd(N, Dest::Global(g, 8, Size::B), Src::Imm(1), Ordering::SeqCst),
+            Instruction::FFIRet(Src::Imm(0)),
+        ];
+        assert_eq!(sandboxed(fetch_add, global()).err(),
+            Some(CodegenError::OutsideGlobal { ir_index: 1, global: g, offset: 8, n_bytes: 1 }));
+    }
+
+    extern "C" fn mix(a: u64, b: u64, c: u64, d: u64, e: u64, f: u64) -> u64 {
+        [a, b, c, d, e, f].iter().fold(0, |hash, x| hash.rotate_left(11) ^ x)
+    }
+
+    // FFIBegin and FFICall both have the argument registers full while they work out addresses,
+    // so indexing and following pointers mustn't touch them. (a sandbox can't index the stack, so p goes straight there)
+    fn indexed_args(sandbox: bool) -> Object {
+        let q = Size::Q;
+        // p[i], where the index i is at -8 and the pointer p at -24
+        let element = |disp| Addr { chain: Chain::new(&[-24]), index: Some((-8, q)), disp };
+        let at_rbp = Addr { chain: Chain::new(&[]), index: Some((-8, q)), disp: -32 };
+        let instructions = vec![
+            Instruction::FFIBegin(64, [
+                Dest::Here(-8, q),
+                if sandbox { Dest::Here(-24, q) } else { Dest::At(at_rbp, q) },  // p, by way of the index (which used to go in rdx)
+                Dest::At(element(0), q),  // (and p in rcx)
+                Dest::Here(-32, q),
+                Dest::Here(-40, q),
+                Dest::Here(-48, q),
+            ]),
+            Instruction::FFICall(Dest::At(element(8), q), [
+                Src::Here(-32, q),
+                Src::Here(-40, q),
+                Src::At(element(0), q),
+                Src::Here(-48, q),
+                Src::At(element(0), q),  // after rdx and rcx are set
+                Src::Ptr(-24, 0, q),
+            ], FFIFunction::new("mix", mix)),
+            Instruction::FFIRet(Src::At(element(8), q)),
+        ];
+        Object { instructions, data: vec![], globals: vec![] }
+    }
+
+    #[test]
+    fn indexing_leaves_the_args_alone() {
+        let expected = mix(4000, 50000, 300, 600000, 300, 9);
+        let object = indexed_args(false);
+
+        // arg0 is i and arg1 is p
+        let mut buffer = [9u64, 0, 0, 0];
+        let args = [1, buffer.as_mut_ptr() as u64, 300, 4000, 50000, 600000];
+        let jit: JitFn<(), u64> = JitFn::new(|addr| object.codegen(addr as u64).unwrap());
+        assert_eq!(unsafe { jit.run_with_args(args) }, Ok(expected));
+        assert_eq!(buffer, [9, 300, expected, 0]);
+
+        let mut buffer = [9u64, 0, 0, 0];
+        let args = [1, buffer.as_mut_ptr() as u64, 300, 4000, 50000, 600000];
+        let mut interp = InterpreterFn::new(indexed_args(false), 1024);
+        unsafe { interp.use_host_memory() };
+        assert_eq!(interp.run(args[0], args[1], args[2], args[3], args[4], args[5]).unwrap(), expected);
+        assert_eq!(buffer, [9, 300, expected, 0]);
+
+        // in a sandbox, the pointer is an offset into the linear memory
+        let options = Options { sandbox: true, ..Options::default() };
+        let object = indexed_args(true);
+        let mut jit: JitFn<(), u64> = JitFn::new(|addr| object.codegen_with(addr as u64, options).unwrap());
+        let mut memory = vec![0; 48];
+        memory[16..24].clone_from_slice(&9u64.to_le_bytes());
+        jit.set_memory(memory);
+        assert_eq!(unsafe { jit.run_with_args([1, 16, 300, 4000, 50000, 600000]) }, Ok(expected));
+        let memory = jit.read_memory();
+        assert_eq!(memory[24..32], 300u64.to_le_bytes());
+        assert_eq!(memory[32..40], expected.to_le_bytes());
+    }
+
+    // Copy(dest, Src::Imm(x)) stores straight from the instruction when it can, and through rax when it can't
+    #[test]
+    fn immediates_everywhere() {
+        const FILL: u64 = 0x5555_5555_5555_5555;
+        let g = GlobalId(0);
+        for sz in [Size::B, Size::H, Size::D, Size::Q] {
+            let mask = u64::MAX >> (64 - 8 * sz.n_bytes());
+            // arg0 is a pointer to a buffer full of FILL, arg1 is FILL and arg2 is 1
+            let element = Addr { chain: Chain::new(&[-8]), index: Some((-24, sz)), disp: 0 };
+            let on_stack = Addr { chain: Chain::new(&[]), index: None, disp: -16 };
+            let forms = [
+                (Dest::Nowhere, Src::Imm(FILL)),
+                (Dest::Here(-16, sz), Src::Here(-16, Size::Q)),
+                (Dest::At(on_stack, sz), Src::Here(-16, Size::Q)),
+                (Dest::Global(g, 8, sz), Src::Global(g, 8, Size::Q)),
+                (Dest::Ptr(-8, 8, sz), Src::Ptr(-8, 8, Size::Q)),
+                (Dest::At(element, sz), Src::At(element, Size::Q)),
+            ];
+            for (dest, stored) in forms {
+                for x in [0, 0x7fff_ffff, 0x8000_0000, u32::MAX as u64, i32::MIN as i64 as u64, u64::MAX] {
+                    let object = || Object {
+                        instructions: vec![
+                            Instruction::FFIBegin(24, [Dest::Here(-8, Size::Q), Dest::Here(-16, Size::Q), Dest::Here(-24, Size::Q), N, N, N]),
+                            Instruction::Copy(dest, Src::Imm(x), Count(1)),
+                            Instruction::FFIRet(stored),
+                        ],
+                        data: vec![],
+                        globals: vec![Blob { name: "g".to_string(), align: 8, bytes: FILL.to_le_bytes().repeat(2) }],
+                    };
+                    let expected = match dest {
+                        Dest::Nowhere => FILL,
+                        _ => FILL & !mask | x & mask,
+                    };
+
+                    let mut buffer = [FILL; 4];
+                    let jit: JitFn<(), u64> = JitFn::new(|addr| object().codegen(addr as u64).unwrap());
+                    let result = unsafe { jit.run_with_args([buffer.as_mut_ptr() as u64, FILL, 1, 0, 0, 0]) };
+                    assert_eq!(result, Ok(expected), "jit: {:?} = {:#x}", dest, x);
+
+                    let mut buffer = [FILL; 4];
+                    let mut interp = InterpreterFn::new(object(), 1024);
+                    unsafe { interp.use_host_memory() };
+                    let result = interp.run(buffer.as_mut_ptr() as u64, FILL, 1, 0, 0, 0).unwrap();
+                    assert_eq!(result, expected, "interpreter: {:?} = {:#x}", dest, x);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn pic_is_the_same_anywhere() {
+        let q = Size::Q;
+        let object = Object {
+            instructions: vec![
+                Instruction::FFIBegin(24, [Dest::Here(-8, q), N, N, N, N, N]),
+                Instruction::Copy(Dest::Here(-16, q), Src::LabelAddr(Label(3)), Count(1)),
+                Instruction::Switch(Src::Here(-8, q), Label(2), vec![Label(0), Label(1)]),
+                Instruction::Label(Label(0)),
+                Instruction::FFICall(Dest::Here(-24, q), [Src::Imm(1), Src::Imm(2), Src::Imm(3), Src::Imm(4), Src::Imm(5), Src::Imm(6)], FFIFunction::new("mix", mix)),
+                Instruction::FFIRet(Src::Here(-24, q)),
+                Instruction::Label(Label(1)),
+                Instruction::Copy(Dest::Here(-24, q), Src::DataAddr(DataId(0)), Count(1)),
+                Instruction::FFIRet(Src::Ptr(-24, 0, q)),
+                Instruction::Label(Label(2)),
+                Instruction::Copy(Dest::Global(GlobalId(0), 0, q), Src::Here(-8, q), Count(1)),
+                Instruction::JmpIndirect(Src::Here(-16, q)),
+                Instruction::Label(Label(3)),
+                Instruction::FFIRet(Src::Global(GlobalId(0), 0, q)),
+            ],
+            data: vec![Blob { name: "d".to_string(), align: 8, bytes: 0x1234u64.to_le_bytes().to_vec() }],
+            globals: vec![Blob { name: "g".to_string(), align: 8, bytes: vec![0; 8] }],
+        };
+        let pic = Options { pic: true, ..Options::default() };
+        let (here, there) = (object.codegen_with(0, pic).unwrap(), object.codegen_with(0xdead_0000, pic).unwrap());
+        assert!(here.position_independent);
+        assert_eq!(here.bytes, there.bytes);
+        assert_ne!(object.codegen(0).unwrap().bytes, object.codegen(0xdead_0000).unwrap().bytes);
+
+        // and it runs from wherever it's put
+        let f: JitFn<u64, u64> = JitFn::from_image(there);
+        assert_eq!(unsafe { f.run(0) }, Ok(mix(1, 2, 3, 4, 5, 6)));
+        assert_eq!(unsafe { f.run(1) }, Ok(0x1234));
+        assert_eq!(unsafe { f.run(9) }, Ok(9));
+    }
+
+    #[test]
+    fn data_gets_its_own_pages() {
+        let object = Object {
+            instructions: vec![
+                Instruction::FFIBegin(8, [N; 6]),
+                Instruction::Copy(Dest::Here(-8, Size::Q), Src::DataAddr(DataId(1)), Count(1)),
+                Instruction::FFIRet(Src::Ptr(-8, 0, Size::Q)),
+            ],
+            data: vec![
+                Blob { name: "a".to_string(), align: 1, bytes: vec![1; 5000] },
+                Blob { name: "b".to_string(), align: 8, bytes: 0x1234_5678u64.to_le_bytes().to_vec() },
+            ],
+            globals: vec![Blob { name: "g".to_string(), align: 8, bytes: vec![0; 8] }],
+        };
+        let image = object.codegen(0).unwrap();
+        assert_eq!((image.read_only.start % 4096, image.read_only.end % 4096), (0, 0));
+        assert!(image.read_only.start > 0 && image.read_only.len() == 8192);
+        assert_eq!(image.globals[0].start, image.read_only.end);
+
+        // (so it can still be read)
+        let jit: JitFn<(), u64> = JitFn::new(|addr| object.codegen(addr as u64).unwrap());
+        assert_eq!(unsafe { jit.run_with_args([0; 6]) }, Ok(0x1234_5678));
+
+        let undefined = Object { data: vec![], ..object };
+        assert_eq!(undefined.codegen(0).err(), Some(CodegenError::DataNotDefined(DataId(1))));
+    }
+
+    #[test]
+    fn interrupts_stop_one_run() {
+        let object = Object { data: vec![], globals: vec![], instructions: vec![
+            Instruction::FFIBegin(8, [N; 6]),
+            Instruction::Label(Label(0)),
+            Instruction::FFIRet(Src::Imm(7)),
+        ]};
+        let image = object.codegen_with(0, Options { pic: true, interruptible: true, ..Options::default() }).unwrap();
+        let f: JitFn<u64, u64> = JitFn::from_image(image);
+
+        // raised before the code starts, it stops the run as soon as it's entered
+        let mut stale = None;
+        let result = unsafe { f.run_with_handle(0, |handle| { handle.interrupt(); stale = Some(handle) }) };
+        assert_eq!(result, Err(Trap { code: TrapCode::Interrupted, ir_index: 1 }));
+
+        // a handle from a run that's over (say a timeout that fired too late) doesn't stop the next one
+        let mut late = None;
+        assert_eq!(unsafe { f.run_with_handle(0, |handle| late = Some(handle)) }, Ok(7));
+        late.unwrap().interrupt();
+        stale.unwrap().interrupt();
+        assert_eq!(unsafe { f.run(0) }, Ok(7));
+        assert_eq!(unsafe { f.run_with_handle(0, |_| {}) }, Ok(7));
+    }
+
+    #[test]
+    fn interrupt_from_another_thread() {
+        // loop: jmp loop
+        let object = Object { data: vec![], globals: vec![], instructions: vec![
+            Instruction::FFIBegin(8, [N; 6]),
+            Instruction::Label(Label(0)),
+            Instruction::JIf(Src::Imm(1), Label(0)),
+        ]};
+        let image = object.codegen_with(0, Options { pic: true, interruptible: true, ..Options::default() }).unwrap();
+        let f: JitFn<u64, u64> = JitFn::from_image(image);
+
+        for _ in 0..2 {
+            let mut watchdog = None;
+            let result = unsafe { f.run_with_handle(0, |handle| watchdog = Some(std::thread::spawn(move || {
+                std::thread::sleep(std::time::Duration::from_millis(20));
+                handle.interrupt();
+            }))) };
+            assert_eq!(result, Err(Trap { code: TrapCode::Interrupted, ir_index: 2 }));
+            watchdog.unwrap().join().unwrap();
+        }
+    }
+
+    #[test]
+    fn out_of_fuel_where_it_stopped() {
+        // counts arg0 down to 0
+        let object = || Object { data: vec![], globals: vec![], instructions: vec![
+            Instruction::FFIBegin(8, [Dest::Here(-8, Size::Q), N, N, N, N, N]),
+            Instruction::Label(Label(0)),
+            Instruction::SubChecked(Dest::Here(-8, Size::Q), Src::Here(-8, Size::Q), Src::Imm(1), Signedness::Unsigned, Label(1)),
+            Instruction::JIf(Src::Imm(1), Label(0)),
+            Instruction::Label(Label(1)),
+            Instruction::FFIRet(Src::Imm(7)),
+        ]};
+        let out_of_fuel = |ir_index| Err(Trap { code: TrapCode::OutOfFuel, ir_index });
+
+        // the JIT spends fuel on entry and at labels, and traps at whatever comes after where it ran out
+        let image = object().codegen_with(0, Options { pic: true, fuel: true, ..Options::default() }).unwrap();
+        let mut jit: JitFn<u64, u64> = JitFn::from_image(image);
+        for (fuel, result) in [(0, out_of_fuel(1)), (1, out_of_fuel(2)), (3, out_of_fuel(2)), (4, out_of_fuel(5)), (5, Ok(7))] {
+            jit.set_fuel(fuel);
+            assert_eq!(unsafe { jit.run(2) }, result, "with {} fuel", fuel);
+        }
+
+        // the interpreter spends it on every instruction, and traps at the one it couldn't run
+        let mut interp = InterpreterFn::new(object(), 1024);
+        for (fuel, ir_index) in [(0, 0), (1, 1), (2, 2), (5, 2), (10, 5)] {
+            interp.set_fuel(fuel);
+            match interp.run(2, 0, 0, 0, 0, 0) {
+                Err(crate::interpreter_fn::InterpError::Trap(trap)) => assert_eq!(Err(trap), out_of_fuel(ir_index), "with {} fuel", fuel),
+                result => panic!("{:?}", result),
+            }
+        }
+        interp.set_fuel(11);
+        assert_eq!(interp.run(2, 0, 0, 0, 0, 0).unwrap(), 7);
+    }
+
+    #[test]
+    fn frames_bigger_than_a_page() {
+        // arg0 goes in the deepest Q of the frame, and comes back out from there
+        let object = |n_bytes: u64| Object { data: vec![], globals: vec![], instructions: vec![
+            Instruction::FFIBegin(n_bytes, [Dest::Here(-8, Size::Q), N, N, N, N, N]),
+            Instruction::Copy(Dest::Here(-(n_bytes as i32), Size::Q), Src::Here(-8, Size::Q), Count(1)),
+            Instruction::Copy(Dest::Here(-8, Size::Q), Src::Imm(0), Count(1)),
+            Instruction::FFIRet(Src::Here(-(n_bytes as i32), Size::Q)),
+        ]};
+        // test QWORD PTR [rsp], rsp
+        let probes = |code: &[u8]| code.windows(4).any(|w| w == [0x48, 0x85, 0x24, 0x24]);
+        // sub rsp, imm32: once a page in the loop, then once more for what's left if it's not a whole page
+        let subs = |code: &[u8]| code.windows(3).filter(|w| *w == [0x48, 0x81, 0xec]).count();
+
+        for n_bytes in [PAGE_SIZE, PAGE_SIZE + 8, 2 * PAGE_SIZE, 3 * PAGE_SIZE + 24, 16 * PAGE_SIZE] {
+            let image = object(n_bytes).codegen_pic().unwrap();
+            assert_eq!(probes(&image.bytes), n_bytes > PAGE_SIZE, "{} bytes", n_bytes);
+            if n_bytes > PAGE_SIZE {
+                assert_eq!(subs(&image.bytes), if n_bytes.is_multiple_of(PAGE_SIZE) { 1 } else { 2 }, "{} bytes", n_bytes);
+            }
+            let f: JitFn<u64, u64> = JitFn::from_image(image);
+            for arg in [0, 0x1122_3344_5566_7788] {
+                assert_eq!(unsafe { f.run(arg) }, Ok(arg), "{} bytes", n_bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_ir_is_an_error() {
+        let codegen = |body: Instruction, sandbox| {
+            let object = Object { data: vec![], globals: vec![], instructions: vec![Instruction::FFIBegin(16, [N; 6]), body, Instruction::FFIRet(Src::Imm(0))] };
+            object.codegen_with(0, Options { sandbox, ..Options::default() }).err()
+        };
+        let q = Dest::Here(-8, Size::Q);
+
+        assert_eq!(codegen(Instruction::Copy(q, Src::Here(-16, Size::D), Count(2)), false), Some(CodegenError::SizeMismatch { ir_index: 1 }));
+        assert_eq!(codegen(Instruction::Copy(q, Src::Here(-16, Size::D), Count(1)), false), None);
+        assert_eq!(codegen(Instruction::AtomicLoad(q, Src::Here(-16, Size::Q), Ordering::Release), false),
+            Some(CodegenError::BadOrdering { ir_index: 1, ordering: Ordering::Release }));
+        assert_eq!(codegen(Instruction::AtomicStore(q, Src::Imm(0), Ordering::Acquire), false),
+            Some(CodegenError::BadOrdering { ir_index: 1, ordering: Ordering::Acquire }));
+        assert_eq!(codegen(Instruction::Fence(Ordering::Relaxed), false), Some(CodegenError::BadOrdering { ir_index: 1, ordering: Ordering::Relaxed }));
+        assert_eq!(codegen(Instruction::AtomicLoad(q, Src::Uninitialized, Ordering::Acquire), false), Some(CodegenError::NotInMemory { ir_index: 1 }));
+        for place_is_nowhere in [
+            Instruction::AtomicStore(N, Src::Imm(0), Ordering::Release),
+            Instruction::FetchAdd(q, N, Src::Imm(1), Ordering::SeqCst),
+            Instruction::Swap(q, N, Src::Imm(1), Ordering::SeqCst),
+            Instruction::CompareExchange(q, N, Src::Imm(0), Src::Imm(1), Ordering::SeqCst),
+        ] {
+            assert_eq!(codegen(place_is_nowhere, false), Some(CodegenError::NotInMemory { ir_index: 1 }));
+        }
+
+        assert_eq!(codegen(Instruction::JmpIndirect(Src::Here(-8, Size::Q)), false), None);
+        assert_eq!(codegen(Instruction::JmpIndirect(Src::Here(-8, Size::Q)), true), Some(CodegenError::NotInSandbox { ir_index: 1 }));
+        let indexed = Addr { chain: Chain::new(&[]), index: Some((-16, Size::Q)), disp: -8 };
+        assert_eq!(codegen(Instruction::Copy(q, Src::At(indexed, Size::Q), Count(1)), true), Some(CodegenError::IndexedStack { ir_index: 1 }));
+
+        let undefined = Instruction::Copy(Dest::Global(GlobalId(1), 0, Size::Q), Src::Imm(0), Count(1));
+        assert_eq!(codegen(undefined, false), Some(CodegenError::GlobalNotDefined(GlobalId(1))));
+
+        let frame = |n_bytes, max_frame_size| {
+            let object = Object { data: vec![], globals: vec![], instructions: vec![Instruction::FFIBegin(n_bytes, [N; 6]), Instruction::FFIRet(Src::Imm(0))] };
+            object.codegen_with(0, Options { max_frame_size, ..Options::default() }).err()
+        };
+        assert_eq!(frame(4096, Some(4096)), None);
+        assert_eq!(frame(4097, Some(4096)), Some(CodegenError::FrameTooBig { ir_index: 0, n_bytes: 4097, max: 4096 }));
+        assert_eq!(frame(1 << 20, None), None);
+        // rsp only moves by an i32
+        assert_eq!(frame(1 << 31, None), Some(CodegenError::FrameTooBig { ir_index: 0, n_bytes: 1 << 31, max: i32::MAX as u64 }));
+        assert_eq!(frame(1 << 31, Some(u64::MAX)), Some(CodegenError::FrameTooBig { ir_index: 0, n_bytes: 1 << 31, max: i32::MAX as u64 }));
+    }
+}
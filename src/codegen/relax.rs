@@ -0,0 +1,190 @@
+use crate::instruction::Label;
+
+// A jump whose encoding isn't decided until `finalize`.
+// It's always written to `code` in its long (rel32) form; relaxation may shrink it to rel8.
+pub(super) struct Branch {
+    pub at: usize,
+    pub label: Label,
+
+    // the condition nibble of a jcc (0x5 for jnz, 0x3 for jae...), or None for a jmp
+    pub condition: Option<u8>,
+}
+
+impl Branch {
+    pub fn long_len(&self) -> usize {
+        match self.condition {
+            // jmp rel32
+            None => 5,
+            // jcc rel32
+            Some(_) => 6,
+        }
+    }
+
+    pub fn short_len(&self) -> usize {
+        // jmp rel8 or jcc rel8
+        2
+    }
+
+    fn write(&self, code: &mut Vec<u8>, short: bool, offset: isize) {
+        match (short, self.condition) {
+            (true, None) => code.extend([0xeb, offset as i8 as u8]),
+            (true, Some(cc)) => code.extend([0x70 | cc, offset as i8 as u8]),
+            (false, None) => {
+                code.push(0xe9);
+                code.extend((offset as i32).to_le_bytes());
+            }
+            (false, Some(cc)) => {
+                code.extend([0x0f, 0x80 | cc]);
+                code.extend((offset as i32).to_le_bytes());
+            }
+        }
+    }
+}
+
+// Where everything ends up once some of the branches have been shortened
+pub(super) struct Layout {
+    // (position of the branch in the unrelaxed code, total bytes saved by it and every branch before it)
+    savings: Vec<(usize, usize)>,
+}
+
+impl Layout {
+    fn new(branches: &[Branch], short: &[bool]) -> Self {
+        let mut savings = vec![];
+        let mut total = 0;
+        for (branch, &short) in branches.iter().zip(short) {
+            if short { total += branch.long_len() - branch.short_len(); }
+            savings.push((branch.at, total));
+        }
+        Layout { savings }
+    }
+
+    // maps a position in the unrelaxed code to a position in the relaxed code
+    // (only branches that start strictly before `position` move it)
+    pub fn map(&self, position: usize) -> usize {
+        let n_before = self.savings.partition_point(|(at, _)| *at < position);
+        if n_before == 0 { return position }
+        position - self.savings[n_before - 1].1
+    }
+}
+
+// Picks the shortest encoding for every branch and rewrites the code to use it.
+// `locate` finds the (unrelaxed) position of a label.
+//
+// Everything starts short and branches are only ever lengthened, so this always terminates.
+// None of it depends on the base address, so the code length doesn't either.
+pub(super) fn relax(code: &[u8], branches: &[Branch], locate: impl Fn(Label) -> usize) -> (Vec<u8>, Layout) {
+    let mut short = vec![true; branches.len()];
+    let layout = loop {
+        let layout = Layout::new(branches, &short);
+        let mut changed = false;
+        for (branch, short) in branches.iter().zip(short.iter_mut()) {
+            if !*short { continue }
+            let end = layout.map(branch.at) + branch.short_len();
+            let offset = layout.map(locate(branch.label)) as isize - end as isize;
+            if i8::try_from(offset).is_err() {
+                *short = false;
+                changed = true;
+            }
+        }
+        if !changed { break layout }
+    };
+
+    let mut relaxed = Vec::with_capacity(code.len());
+    let mut copied_up_to = 0;
+    for (branch, &short) in branches.iter().zip(short.iter()) {
+        relaxed.extend(&code[copied_up_to..branch.at]);
+        let len = if short { branch.short_len() } else { branch.long_len() };
+        let end = layout.map(branch.at) + len;
+        let offset = layout.map(locate(branch.label)) as isize - end as isize;
+        branch.write(&mut relaxed, short, offset);
+        copied_up_to = branch.at + branch.long_len();
+    }
+    relaxed.extend(&code[copied_up_to..]);
+
+    (relaxed, layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    enum Piece { Nops(usize), Jmp(u64), Jnz(u64), Label(u64) }
+
+    // lays the pieces out the way Codegen would (every branch long, to be patched later), relaxes them,
+    // and checks every branch in the result lands on its label. returns the relaxed code
+    fn relaxed(pieces: &[Piece]) -> Vec<u8> {
+        let (mut code, mut branches, mut labels) = (vec![], vec![], HashMap::new());
+        for piece in pieces {
+            match *piece {
+                Piece::Nops(n) => code.extend(std::iter::repeat_n(0x90, n)),
+                Piece::Jmp(label) | Piece::Jnz(label) => {
+                    let condition = match piece { Piece::Jnz(_) => Some(0x5), _ => None };
+                    let branch = Branch { at: code.len(), label: Label(label), condition };
+                    code.extend(std::iter::repeat_n(0xcc, branch.long_len()));
+                    branches.push(branch);
+                }
+                Piece::Label(label) => { labels.insert(Label(label), code.len()); }
+            }
+        }
+        let locate = |label| labels[&label];
+        let (relaxed, layout) = relax(&code, &branches, locate);
+
+        let mut at = 0;
+        let mut branches = branches.iter();
+        while at < relaxed.len() {
+            let (len, offset) = match relaxed[at] {
+                0x90 => { at += 1; continue }
+                0xeb | 0x70..=0x7f => (2, relaxed[at + 1] as i8 as isize),
+                0xe9 => (5, i32::from_le_bytes(relaxed[at + 1..at + 5].try_into().unwrap()) as isize),
+                0x0f => (6, i32::from_le_bytes(relaxed[at + 2..at + 6].try_into().unwrap()) as isize),
+                byte => panic!("{:#x} at {}", byte, at),
+            };
+            let branch = branches.next().expect("no more branches");
+            assert_eq!(layout.map(branch.at), at);
+            assert_eq!((at + len) as isize + offset, layout.map(locate(branch.label)) as isize, "the branch at {}", at);
+            at += len;
+        }
+        assert!(branches.next().is_none());
+        relaxed
+    }
+
+    #[test]
+    fn tiny_loops_are_short() {
+        // loop: nop; nop; nop; jnz loop; jmp out; nop; nop; out:
+        let code = relaxed(&[Piece::Label(0), Piece::Nops(3), Piece::Jnz(0), Piece::Jmp(1), Piece::Nops(2), Piece::Label(1)]);
+        assert_eq!(code, [0x90, 0x90, 0x90, 0x75, 0xfb, 0xeb, 0x02, 0x90, 0x90]);
+    }
+
+    #[test]
+    fn far_branches_stay_long() {
+        // 127 bytes away fits in a rel8, and 128 doesn't
+        let code = relaxed(&[Piece::Jmp(0), Piece::Nops(127), Piece::Label(0)]);
+        assert_eq!(code[..2], [0xeb, 0x7f]);
+        let code = relaxed(&[Piece::Jmp(0), Piece::Nops(128), Piece::Label(0)]);
+        assert_eq!(code[..5], [0xe9, 0x80, 0x00, 0x00, 0x00]);
+
+        // backwards, -128 fits (counting the branch itself)
+        let code = relaxed(&[Piece::Label(0), Piece::Nops(126), Piece::Jnz(0)]);
+        assert_eq!(code[126..], [0x75, 0x80]);
+        let code = relaxed(&[Piece::Label(0), Piece::Nops(127), Piece::Jnz(0)]);
+        assert_eq!(code[127..], [0x0f, 0x85, 0x7b, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn branches_grow_when_others_do() {
+        // the first jmp would reach over a short second one (124 + 2 bytes), but the second one is long, so it can't
+        let code = relaxed(&[
+            Piece::Jmp(1), Piece::Nops(124), Piece::Jmp(2), Piece::Label(1), Piece::Nops(200), Piece::Label(2),
+        ]);
+        assert_eq!((code[0], code[129]), (0xe9, 0xe9));
+
+        // and the same, but with the second jmp only long because of a third
+        let code = relaxed(&[
+            Piece::Jmp(1), Piece::Nops(124), Piece::Jmp(2), Piece::Label(1), Piece::Nops(124), Piece::Jnz(3), Piece::Label(2),
+            Piece::Nops(200), Piece::Label(3),
+        ]);
+        assert_eq!((code[0], code[129], code[258]), (0xe9, 0xe9, 0x0f));
+    }
+}
@@ -0,0 +1,120 @@
+use std::io::{self, BufRead, Write};
+
+use crate::fail;
+use crate::instruction::{Label, Size};
+use crate::interpreter_fn::{InterpreterFn, InterpreterSession, Event};
+
+const HELP: &str = "\
+s [n]          step (n times, stopping at breakpoints and watchpoints)
+c              continue to a breakpoint, a watchpoint, or the end
+b <index>      break before the instruction at index
+bl <n>         break at Label(n)
+d <index>      delete the breakpoint at index
+w <offset> <B|H|D|Q>
+               stop when Here(offset, size) changes
+f              dump the frame
+l              list the code around ip
+q              quit";
+
+// `pinkdrone debug file.pd [args...]`
+pub fn debug(path: &str, args: &[String]) {
+    let code = std::fs::read_to_string(path).unwrap_or_else(|e| fail(format!("couldn't read {}: {}", path, e)));
+    let (object, _) = crate::parser::parse(&code).unwrap_or_else(|error| fail(error));
+    let code = object.instructions.clone();
+
+    let mut fn_args = [0; 6];
+    for (arg, text) in fn_args.iter_mut().zip(args) {
+        *arg = match parse_u64(text) {
+            Some(value) => value,
+            None => fail(format!("not a number: {}", text)),
+        };
+    }
+
+    let interp = InterpreterFn::new(object, 1024);
+    let mut session = InterpreterSession::new(&interp, fn_args);
+    println!("{} instructions. h for help", code.len());
+    list(&code, session.ip());
+
+    let stdin = io::stdin();
+    loop {
+        print!("(pd) ");
+        io::stdout().flush().expect("couldn't write to stdout");
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).expect("couldn't read stdin") == 0 { return }
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words.as_slice() {
+            [] => {}
+            ["h"] | ["help"] => println!("{}", HELP),
+            ["q"] | ["quit"] => return,
+            ["f"] | ["frame"] => print!("{}", session.frame()),
+            ["l"] | ["list"] => list(&code, session.ip()),
+            ["b", index] => match index.parse() {
+                Ok(index) => session.break_at(index),
+                Err(_) => println!("not an index: {}", index),
+            },
+            ["bl", n] => match parse_u64(n) {
+                Some(n) if session.break_at_label(Label(n)) => {}
+                _ => println!("no such label: {}", n),
+            },
+            ["d", index] => match index.parse() {
+                Ok(index) => session.remove_breakpoint(index),
+                Err(_) => println!("not an index: {}", index),
+            },
+            ["w", offset, size] => match (offset.parse(), parse_size(size)) {
+                (Ok(offset), Some(size)) => session.watch(offset, size),
+                _ => println!("usage: w <offset> <B|H|D|Q>"),
+            },
+            ["s"] | ["step"] | ["s", _] | ["step", _] | ["c"] | ["continue"] => {
+                if session.is_finished() {
+                    println!("the run is over");
+                    continue;
+                }
+                let event = match words.as_slice() {
+                    ["c"] | ["continue"] => session.run(),
+                    [_, n] => match n.parse() {
+                        Ok(n) => session.step_by(n),
+                        Err(_) => {
+                            println!("not a number: {}", n);
+                            continue;
+                        }
+                    },
+                    _ => session.step(),
+                };
+                match event {
+                    Event::Stepped | Event::Breakpoint(_) => {}
+                    Event::Watchpoint { offset, old, new } => println!("bp{}: {:x?} -> {:x?}", offset, old, new),
+                    Event::Returned(value) => println!("returned {:#x}", value),
+                    Event::Failed(error) => println!("failed: {:?}", error),
+                }
+                if !session.is_finished() { list(&code, session.ip()) }
+            }
+            _ => println!("what? (h for help)"),
+        }
+    }
+}
+
+// a few instructions either side of ip
+fn list(code: &[crate::instruction::Instruction], ip: usize) {
+    for (i, instruction) in code.iter().enumerate().skip(ip.saturating_sub(2)).take(5) {
+        let marker = if i == ip { "=>" } else { "  " };
+        println!("{} {:4} {:?}", marker, i, instruction);
+    }
+}
+
+fn parse_u64(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_size(text: &str) -> Option<Size> {
+    match text {
+        "B" | "b" => Some(Size::B),
+        "H" | "h" => Some(Size::H),
+        "D" | "d" => Some(Size::D),
+        "Q" | "q" => Some(Size::Q),
+        _ => None,
+    }
+}
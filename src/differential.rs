@@ -0,0 +1,402 @@
+use crate::fail;
+use crate::codegen::CodegenError;
+use crate::instruction::{Instruction, Dest, Src, Size, Count, Label, GlobalId, Signedness};
+use crate::interpreter_fn::{InterpreterFn, InterpError};
+use crate::jit_fn::JitFn;
+use crate::object::{Object, Blob};
+use crate::trap::Trap;
+
+// runs the same Object through the JIT and the interpreter with lots of different args, and checks they agree:
+// on the return value or trap, on the frame as it was when the code returned, and on the globals.
+// only makes sense for code whose results don't depend on addresses (AddrOf, DataAddr, pointers from FFICall),
+// since those are different in each engine, and the code has to start with its FFIBegin (or it's Mismatch::NoFFIBegin)
+
+// what a run looked like from outside
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Observed {
+    pub ending: Ending,
+    pub frame: Vec<u8>,  // bp - n_bytes up to bp, as it was when the code returned (all zero if it didn't)
+    pub globals: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Ending {
+    Returned(u64),
+    Trapped(Trap),
+    Stopped(usize),  // only while looking for the first divergent instruction: about to run the one at this index
+}
+
+#[derive(Debug)]
+pub enum Mismatch {
+    // the interpreter says the code is wrong for these args, so there's nothing to hold the JIT to
+    Invalid { args: [u64; 6], error: InterpError },
+    Codegen(CodegenError),
+    // there's no FFIBegin at the start, so no frame to look at
+    NoFFIBegin,
+    // a run ended in a way the instrumented code can't: trapping at an instruction it doesn't have,
+    // or not stopping when its budget ran out
+    Unexplained { args: [u64; 6], budget: u64, ending: Ending },
+    Diverged {
+        args: [u64; 6],
+        ir_index: usize,  // the first instruction after which the engines disagree
+        interpreter: Observed,
+        jit: Observed,
+    },
+}
+
+// the code, rewritten so that a run can be stopped after any number of instructions and its frame looked at.
+// before each instruction there's
+//     stopped_at = <index>
+//     budget -= 1, or if it was already 0, go to the capture block
+// the frame gets zeroed after the FFIBegin (so bytes that were never written are the same in both engines),
+// and FFIRet becomes `returned = src` then a jump to the capture block, which copies the frame into a global and returns
+struct Instrumented {
+    object: Object,
+    original: Vec<usize>,  // for each instruction, the index of the one in the original code it's for
+    n_bytes: u64,
+    n_instructions: usize,  // in the original code
+    budget: GlobalId,
+    stopped_at: GlobalId,
+    returned: GlobalId,
+    frame: GlobalId,
+}
+
+const CAPTURE: Label = Label(u64::MAX);
+const RETURNED: u64 = u64::MAX;  // what stopped_at gets set to by an FFIRet
+
+// every edge value in every position (with the rest zero), then at least `n` altogether,
+// made up of a pseudo-random mix of edge values and anything else
+pub fn argument_vectors(n: usize) -> Vec<[u64; 6]> {
+    const EDGES: [u64; 18] = [
+        0, 1, 2, 0x7f, 0x80, 0xff, 0x100, 0x7fff, 0x8000, 0xffff,
+        0x7fff_ffff, 0x8000_0000, 0xffff_ffff, 0x1_0000_0000,
+        i64::MAX as u64, i64::MIN as u64, u64::MAX - 1, u64::MAX,
+    ];
+
+    let mut vectors = vec![[0; 6]];
+    for position in 0..6 {
+        for edge in EDGES {
+            let mut args = [0; 6];
+            args[position] = edge;
+            vectors.push(args);
+        }
+    }
+
+    // xorshift64
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut random = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    while vectors.len() < n {
+        let mut args = [0; 6];
+        for arg in args.iter_mut() {
+            let r = random();
+            *arg = if r % 2 == 0 { EDGES[(r >> 1) as usize % EDGES.len()] } else { random() };
+        }
+        vectors.push(args);
+    }
+    vectors
+}
+
+// the first args (in order) the engines disagree on
+pub fn compare(object: &Object, all_args: &[[u64; 6]]) -> Result<(), Mismatch> {
+    let instrumented = instrument(object)?;
+    let stack_size = instrumented.n_bytes as usize + 1024;
+    let reference = InterpreterFn::new(copy(object), stack_size);
+    let interp = InterpreterFn::new(copy(&instrumented.object), stack_size);
+    instrumented.object.codegen(0).map_err(Mismatch::Codegen)?;
+    let jit: JitFn<(), u64> = JitFn::new(|addr| instrumented.object.codegen(addr as u64).expect("it worked the first time"));
+
+    for args in all_args.iter().copied() {
+        // the uninstrumented code first, since that's the one that notices it running off the end
+        reset(object, |global, bytes| reference.write_global(global, bytes));
+        if let Err(error @ InterpError::Invalid { .. }) = reference.run(args[0], args[1], args[2], args[3], args[4], args[5]) {
+            return Err(Mismatch::Invalid { args, error });
+        }
+
+        let run_interp = |budget| instrumented.observe(args, budget, |global, bytes| interp.write_global(global, bytes), |global| interp.read_global(global),
+            || match interp.run(args[0], args[1], args[2], args[3], args[4], args[5]) {
+                Ok(value) => Ok(Ok(value)),
+                Err(InterpError::Trap(trap)) => Ok(Err(trap)),
+                Err(error) => Err(Mismatch::Invalid { args, error }),
+            });
+        let run_jit = |budget| instrumented.observe(args, budget, |global, bytes| jit.write_global(global, bytes), |global| jit.read_global(global),
+            || Ok(unsafe { jit.run_with_args(args) }));
+        let both = |budget| -> Result<(Observed, Observed), Mismatch> { Ok((run_interp(budget)?, run_jit(budget)?)) };
+
+        let (interpreter, jit) = both(u64::MAX)?;
+        if interpreter == jit { continue }
+
+        // find the fewest instructions it takes for them to disagree.
+        // (this assumes they never agree again afterwards, which is almost always true)
+        let n_steps = u64::MAX - u64::from_le_bytes(interp.read_global(instrumented.budget).try_into().expect("budget is a Q"));
+        let (mut lo, mut hi) = (0, n_steps);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let (a, b) = both(mid)?;
+            if a == b { lo = mid + 1 } else { hi = mid }
+        }
+        let ir_index = match lo {
+            0 => 0,
+            _ => match both(lo - 1)?.0.ending {
+                Ending::Stopped(ir_index) => ir_index,
+                ending => return Err(Mismatch::Unexplained { args, budget: lo - 1, ending }),
+            },
+        };
+        return Err(Mismatch::Diverged { args, ir_index, interpreter, jit });
+    }
+    Ok(())
+}
+
+fn instrument(object: &Object) -> Result<Instrumented, Mismatch> {
+    let (n_bytes, dests) = match object.instructions.first() {
+        Some(Instruction::FFIBegin(n_bytes, dests)) => (*n_bytes, *dests),
+        _ => return Err(Mismatch::NoFFIBegin),
+    };
+    let n = n_bytes as i32;
+
+    let mut globals = object.globals.clone();
+    let mut global = |name: &str, size: u64| {
+        globals.push(Blob { name: name.to_string(), align: 8, bytes: vec![0; size as usize] });
+        GlobalId(globals.len() - 1)
+    };
+    let (budget, stopped_at, returned, frame) = (global("budget", 8), global("stopped_at", 8), global("returned", 8), global("frame", n_bytes));
+
+    // the args get parked below the frame while it's zeroed
+    let parked = |i: usize| Src::Here(-n - 8 * (i as i32 + 1), Size::Q);
+    let mut code = vec![
+        Instruction::FFIBegin(n_bytes + 48, [0, 1, 2, 3, 4, 5].map(|i| Dest::Here(-n - 8 * (i + 1), Size::Q))),
+        Instruction::Copy(Dest::Here(-n, Size::B), Src::Imm(0), Count(n_bytes)),
+    ];
+    for (i, dest) in dests.iter().enumerate() {
+        if dest.needs_store() {
+            code.push(Instruction::Copy(*dest, parked(i), Count(1)));
+        }
+    }
+    let mut original = vec![0; code.len()];
+
+    let q = |global| (Dest::Global(global, 0, Size::Q), Src::Global(global, 0, Size::Q));
+    for (i, instruction) in object.instructions.iter().enumerate().skip(1) {
+        let check = [
+            Instruction::Copy(q(stopped_at).0, Src::Imm(i as u64), Count(1)),
+            Instruction::SubChecked(q(budget).0, q(budget).1, Src::Imm(1), Signedness::Unsigned, CAPTURE),
+        ];
+        let rewritten = match instruction {
+            Instruction::FFIRet(src) => vec![
+                Instruction::Copy(q(returned).0, *src, Count(1)),
+                Instruction::Copy(q(stopped_at).0, Src::Imm(RETURNED), Count(1)),
+                Instruction::JIf(Src::Imm(1), CAPTURE),
+            ],
+            instruction => vec![instruction.clone()],
+        };
+        let here = match instruction {
+            // jumps go to the label, so that's where they get counted
+            Instruction::Label(_) => rewritten.into_iter().chain(check).collect::<Vec<_>>(),
+            _ => check.into_iter().chain(rewritten).collect(),
+        };
+        original.extend(here.iter().map(|_| i));
+        code.extend(here);
+    }
+
+    code.extend([
+        Instruction::Label(CAPTURE),
+        Instruction::Copy(Dest::Global(frame, 0, Size::B), Src::Here(-n, Size::B), Count(n_bytes)),
+        Instruction::FFIRet(q(returned).1),
+    ]);
+    original.resize(code.len(), object.instructions.len());
+
+    Ok(Instrumented {
+        object: Object { instructions: code, data: object.data.clone(), globals },
+        original, n_bytes, n_instructions: object.instructions.len(), budget, stopped_at, returned, frame,
+    })
+}
+
+impl Instrumented {
+    fn observe(
+        &self, args: [u64; 6], budget: u64,
+        write_global: impl Fn(GlobalId, &[u8]), read_global: impl Fn(GlobalId) -> Vec<u8>,
+        run: impl FnOnce() -> Result<Result<u64, Trap>, Mismatch>,
+    ) -> Result<Observed, Mismatch> {
+        reset(&self.object, &write_global);
+        write_global(self.budget, &budget.to_le_bytes());
+
+        let q = |global| u64::from_le_bytes(read_global(global).try_into().expect("it's a Q"));
+        let unexplained = |ending| Mismatch::Unexplained { args, budget, ending };
+        let ending = match run()? {
+            Err(trap) => match self.original.get(trap.ir_index) {
+                Some(&ir_index) => Ending::Trapped(Trap { ir_index, ..trap }),
+                None => return Err(unexplained(Ending::Trapped(trap))),
+            },
+            Ok(_) if q(self.stopped_at) == RETURNED => Ending::Returned(q(self.returned)),
+            Ok(_) => match q(self.stopped_at) as usize {
+                ir_index if ir_index < self.n_instructions => Ending::Stopped(ir_index),
+                ir_index => return Err(unexplained(Ending::Stopped(ir_index))),
+            },
+        };
+        let n_globals = self.object.globals.len() - 4;
+        Ok(Observed {
+            ending,
+            frame: read_global(self.frame),
+            globals: (0..n_globals).map(|i| read_global(GlobalId(i))).collect(),
+        })
+    }
+}
+
+// globals keep their values between runs, so every run starts by putting them back
+fn reset(object: &Object, write_global: impl Fn(GlobalId, &[u8])) {
+    for (i, global) in object.globals.iter().enumerate() {
+        write_global(GlobalId(i), &global.bytes);
+    }
+}
+
+fn copy(object: &Object) -> Object {
+    Object { instructions: object.instructions.clone(), data: object.data.clone(), globals: object.globals.clone() }
+}
+
+// `pinkdrone diff file.pd [n]`
+pub fn diff(path: &str, n: Option<&String>) {
+    let code = std::fs::read_to_string(path).unwrap_or_else(|e| fail(format!("couldn't read {}: {}", path, e)));
+    let (object, _) = crate::parser::parse(&code).unwrap_or_else(|error| fail(error));
+    let n = match n.map(|n| n.parse()) {
+        Some(Ok(n)) => n,
+        Some(Err(_)) => fail(format!("not a number: {}", n.expect("it's there"))),
+        None => 200,
+    };
+
+    let all_args = argument_vectors(n);
+    match compare(&object, &all_args) {
+        Ok(()) => println!("the JIT and the interpreter agree on all {} arg vectors", all_args.len()),
+        Err(Mismatch::Diverged { args, ir_index, interpreter, jit }) => fail(format!(
+            "with args {:x?}, they first disagree after {}: {:?}\ninterpreter: {:x?}\njit:         {:x?}",
+            args, ir_index, object.instructions.get(ir_index), interpreter, jit
+        )),
+        Err(Mismatch::NoFFIBegin) => fail(format!("{} has to start with an ffinyeh", path)),
+        Err(mismatch) => fail(format!("{:?}", mismatch)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::Ordering;
+    use super::*;
+
+    const Q: Size = Size::Q;
+
+    // the args go at -8, -16, .. -48
+    fn object(n_bytes: u64, body: Vec<Instruction>, globals: Vec<Blob>) -> Object {
+        let mut instructions = vec![Instruction::FFIBegin(n_bytes, [1, 2, 3, 4, 5, 6].map(|i| Dest::Here(-8 * i, Q)))];
+        instructions.extend(body);
+        Object { instructions, data: vec![], globals }
+    }
+
+    fn arg(i: i32) -> Src {
+        Src::Here(-8 * (i + 1), Q)
+    }
+
+    fn global(bytes: Vec<u8>) -> Blob {
+        Blob { name: "g".to_string(), align: 8, bytes }
+    }
+
+    fn agree(object: Object) {
+        if let Err(mismatch) = compare(&object, &argument_vectors(100)) {
+            panic!("{:x?}", mismatch);
+        }
+    }
+
+    #[test]
+    fn copies_of_more_than_one() {
+        agree(object(96, vec![
+            Instruction::Copy(Dest::Here(-64, Size::B), Src::Here(-16, Size::B), Count(16)),
+            // each Q is one byte along, so they overlap
+            Instruction::Copy(Dest::Here(-96, Q), Src::Here(-48, Q), Count(4)),
+            Instruction::Copy(Dest::Here(-80, Size::H), Src::Imm(0xabcd), Count(3)),
+            Instruction::FFIRet(Src::Here(-60, Size::D)),
+        ], vec![]));
+    }
+
+    #[test]
+    fn switch() {
+        agree(object(48, vec![
+            Instruction::Switch(arg(0), Label(9), vec![Label(0), Label(1), Label(1), Label(2)]),
+            Instruction::Label(Label(0)), Instruction::FFIRet(Src::Imm(100)),
+            Instruction::Label(Label(1)), Instruction::FFIRet(arg(1)),
+            Instruction::Label(Label(2)), Instruction::Trap(7),
+            Instruction::Label(Label(9)), Instruction::FFIRet(Src::Imm(999)),
+        ], vec![]));
+    }
+
+    #[test]
+    fn checked_arithmetic() {
+        let mut body = vec![];
+        type Operation = fn(Dest, Src, Src, Signedness, Label) -> Instruction;
+        let operations: [Operation; 3] = [Instruction::AddChecked, Instruction::SubChecked, Instruction::MulChecked];
+        for (i, operation) in operations.into_iter().enumerate() {
+            for (j, signedness) in [Signedness::Signed, Signedness::Unsigned].into_iter().enumerate() {
+                let overflow = Label((2 * i + j) as u64);
+                body.extend([
+                    operation(Dest::Here(-56, Q), arg(0), arg(1), signedness, overflow),
+                    operation(Dest::Here(-56, Q), Src::Here(-56, Q), Src::Imm(0x7fff_ffff), signedness, overflow),
+                    Instruction::Label(overflow),
+                ]);
+            }
+        }
+        body.push(Instruction::FFIRet(Src::Here(-56, Q)));
+        agree(object(56, body, vec![]));
+    }
+
+    #[test]
+    fn atomics_and_globals() {
+        let g = GlobalId(0);
+        agree(object(64, vec![
+            Instruction::FetchAdd(Dest::Here(-56, Q), Dest::Global(g, 0, Q), arg(0), Ordering::SeqCst),
+            Instruction::Swap(Dest::Here(-64, Size::D), Dest::Here(-16, Size::D), arg(2), Ordering::AcqRel),
+            Instruction::CompareExchange(Dest::Global(g, 8, Size::H), Dest::Here(-24, Size::B), arg(3), arg(4), Ordering::SeqCst),
+            Instruction::AtomicStore(Dest::Global(g, 12, Size::D), arg(5), Ordering::Release),
+            Instruction::AtomicLoad(Dest::Here(-48, Q), Src::Global(g, 4, Q), Ordering::Acquire),
+            Instruction::FFIRet(Src::Global(g, 0, Q)),
+        ], vec![global((0..16).collect())]));
+    }
+
+    #[test]
+    fn traps() {
+        agree(object(48, vec![
+            Instruction::JIf(arg(0), Label(0)),
+            Instruction::Trap(1),
+            Instruction::Label(Label(0)),
+            Instruction::SubChecked(Dest::Nowhere, arg(0), arg(1), Signedness::Unsigned, Label(1)),
+            Instruction::FFIRet(Src::Imm(0)),
+            Instruction::Label(Label(1)),
+            Instruction::Trap(2),
+        ], vec![]));
+    }
+
+    #[test]
+    fn finds_where_they_diverge() {
+        // an address is different in each engine, so the frame is too, as soon as it's stored
+        let diverges = object(56, vec![
+            Instruction::Copy(Dest::Here(-56, Q), arg(0), Count(1)),
+            Instruction::Copy(Dest::Here(-56, Q), Src::AddrOf(-8), Count(1)),
+            Instruction::FFIRet(Src::Imm(0)),
+        ], vec![]);
+        match compare(&diverges, &argument_vectors(1)) {
+            Err(Mismatch::Diverged { args, ir_index, interpreter, jit }) => {
+                assert_eq!(args, [0; 6]);
+                assert_eq!(ir_index, 2);
+                assert_eq!((interpreter.ending, jit.ending), (Ending::Returned(0), Ending::Returned(0)));
+                assert_ne!(interpreter.frame, jit.frame);
+            }
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn needs_an_ffi_begin() {
+        let object = Object { instructions: vec![Instruction::FFIRet(Src::Imm(0))], data: vec![], globals: vec![] };
+        assert!(matches!(compare(&object, &argument_vectors(1)), Err(Mismatch::NoFFIBegin)));
+        let empty = Object { instructions: vec![], data: vec![], globals: vec![] };
+        assert!(matches!(compare(&empty, &argument_vectors(1)), Err(Mismatch::NoFFIBegin)));
+    }
+}
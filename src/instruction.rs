@@ -1,19 +1,60 @@
-#[derive(Clone, Copy, Debug)]
-pub enum Dest { Nowhere, Ptr(i32, i32, Size), Here(i32, Size) }
+use std::hash::{Hash, Hasher};
 
-#[derive(Clone, Copy, Debug)]
-pub enum Src { Uninitialized, Imm(u64), Ptr(i32, i32, Size), Here(i32, Size) }
+#[derive(Clone, Copy, Debug, Hash)]
+pub enum Dest { Nowhere, Ptr(i32, i32, Size), Here(i32, Size), At(Addr, Size), Global(GlobalId, i32, Size) }
 
-#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+#[derive(Clone, Copy, Debug, Hash)]
+pub enum Src { Uninitialized, Imm(u64), Ptr(i32, i32, Size), Here(i32, Size), At(Addr, Size), Global(GlobalId, i32, Size), LabelAddr(Label), AddrOf(i32), DataAddr(DataId) }
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
 pub enum Size { B, H, D, Q }
 
-#[derive(Clone, Copy, Debug)]
+// most pointers you can follow to find the base of an Addr
+pub const MAX_CHAIN: usize = 4;
+
+// base + index * scale + disp
+// the base starts out as bp, and each offset in `chain` replaces it with the pointer stored at base + offset
+// the index (if any) is the Q stored at bp + offset, scaled by the size of an element
+// so Src::Ptr(a, b, sz) is Src::At(Addr { chain: Chain::new(&[a]), index: None, disp: b }, sz)
+#[derive(Clone, Copy, Debug, Hash)]
+pub struct Addr {
+    pub chain: Chain,
+    pub index: Option<(i32, Size)>,
+    pub disp: i32,
+}
+
+#[derive(Clone, Copy, Debug, Hash)]
+pub struct Chain { len: u8, offsets: [i32; MAX_CHAIN] }
+
+#[derive(Clone, Copy, Debug, Hash)]
 pub struct Count(pub u64);
 
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub enum Signedness { Signed, Unsigned }
+
+// same meaning as std::sync::atomic::Ordering
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Hash)]
+pub enum Ordering { Relaxed, Release, Acquire, AcqRel, SeqCst }
+
 #[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
 pub struct Label(pub u64);
 
+// index into Object::data
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub struct DataId(pub usize);
+
+// index into Object::globals
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, PartialOrd, Ord)]
+pub struct GlobalId(pub usize);
+
+// what FFICall calls. the JIT calls `pointer`, but the interpreter looks up `name` first, so it can be mocked
 #[derive(Clone, Copy, Debug)]
+pub struct FFIFunction {
+    pub name: &'static str,
+    pub pointer: extern fn(u64, u64, u64, u64, u64, u64) -> u64,
+}
+
+#[derive(Clone, Debug, Hash)]
 pub enum Instruction {
     // NOTE: too-small sources will be zero-extended to u64
     // Likewise, too-small destinations will get the low bits of the u64
@@ -25,8 +66,95 @@ pub enum Instruction {
     Copy(Dest, Src, Count),
 
     JIf(Src, Label),
+    // jumps to the label at index `src` in the table, or to the default label if out of range
+    Switch(Src, Label, Vec<Label>),
+    // jumps to an address produced by Src::LabelAddr
+    JmpIndirect(Src),
     Label(Label),
-    FFICall(Dest, [Src; 6], extern fn(u64, u64, u64, u64, u64, u64) -> u64),
+    FFICall(Dest, [Src; 6], FFIFunction),
+
+    // atomics: the memory operand (the Src of a load, the second Dest of the rest) can't be Nowhere
+    AtomicLoad(Dest, Src, Ordering),
+    AtomicStore(Dest, Src, Ordering),
+    // these store the value that was there before into the first Dest
+    FetchAdd(Dest, Dest, Src, Ordering),
+    Swap(Dest, Dest, Src, Ordering),
+    // (old, place, expected, new): if place holds expected, replace it with new
+    // (on failure, the ordering is weakened the same way std's compare_and_swap does)
+    CompareExchange(Dest, Dest, Src, Src, Ordering),
+    Fence(Ordering),
+
+    // dest = a op b, done on all 64 bits (so too-small sources are zero-extended, as usual)
+    // if that overflows, jump to the label instead, leaving dest alone
+    AddChecked(Dest, Src, Src, Signedness, Label),
+    SubChecked(Dest, Src, Src, Signedness, Label),
+    MulChecked(Dest, Src, Src, Signedness, Label),
+
+    // gives up, returning Err(Trap) to whoever's running this
+    Trap(u64),
+}
+
+impl Size {
+    pub(crate) fn n_bytes(self) -> u64 {
+        match self {
+            Size::B => 1,
+            Size::H => 2,
+            Size::D => 4,
+            Size::Q => 8,
+        }
+    }
+}
+
+// by name, since where the function is depends on the process
+impl Hash for FFIFunction {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state)
+    }
+}
+
+impl FFIFunction {
+    pub fn new(name: &'static str, pointer: extern fn(u64, u64, u64, u64, u64, u64) -> u64) -> FFIFunction {
+        FFIFunction { name, pointer }
+    }
+}
+
+impl Ordering {
+    // the combinations std::sync::atomic panics on are rejected here too
+    pub(crate) fn valid_for_load(self) -> bool {
+        !matches!(self, Ordering::Release | Ordering::AcqRel)
+    }
+
+    pub(crate) fn valid_for_store(self) -> bool {
+        !matches!(self, Ordering::Acquire | Ordering::AcqRel)
+    }
+
+    pub(crate) fn valid_for_fence(self) -> bool {
+        self != Ordering::Relaxed
+    }
+}
+
+impl Chain {
+    pub fn new(offsets: &[i32]) -> Chain {
+        assert!(offsets.len() <= MAX_CHAIN, "can't follow more than {} pointers", MAX_CHAIN);
+        let mut chain = Chain { len: offsets.len() as u8, offsets: [0; MAX_CHAIN] };
+        chain.offsets[..offsets.len()].clone_from_slice(offsets);
+        chain
+    }
+
+    pub fn offsets(&self) -> &[i32] {
+        &self.offsets[..self.len as usize]
+    }
+}
+
+impl Addr {
+    // the address Src::Ptr and Dest::Ptr use
+    pub(crate) fn ptr(offset_to_ptr: i32, offset_after_ptr: i32) -> Addr {
+        Addr { chain: Chain::new(&[offset_to_ptr]), index: None, disp: offset_after_ptr }
+    }
+
+    pub(crate) fn offset(self, amt: i32) -> Addr {
+        Addr { disp: self.disp + amt, ..self }
+    }
 }
 
 impl Src {
@@ -41,8 +169,13 @@ impl Src {
         match self {
             Src::Uninitialized => Src::Uninitialized,
             Src::Imm(x) => Src::Imm(x),
+            Src::LabelAddr(l) => Src::LabelAddr(l),
+            Src::AddrOf(stack) => Src::AddrOf(stack),
+            Src::DataAddr(d) => Src::DataAddr(d),
             Src::Ptr(stack, far, sz) => Src::Ptr(stack, far + amt, sz),
-            Src::Here(stack, sz) => Src::Here(stack + amt, sz)
+            Src::Here(stack, sz) => Src::Here(stack + amt, sz),
+            Src::At(addr, sz) => Src::At(addr.offset(amt), sz),
+            Src::Global(global, offset, sz) => Src::Global(global, offset + amt, sz),
         }
     }
 }
@@ -55,11 +188,31 @@ impl Dest {
         }
     }
 
+    pub(crate) fn size(self) -> Option<Size> {
+        match self {
+            Dest::Nowhere => None,
+            Dest::Ptr(_, _, sz) | Dest::Here(_, sz) | Dest::At(_, sz) | Dest::Global(_, _, sz) => Some(sz),
+        }
+    }
+
+    // reading from the place this would write to
+    pub(crate) fn as_src(self) -> Src {
+        match self {
+            Dest::Nowhere => Src::Uninitialized,
+            Dest::Ptr(stack, far, sz) => Src::Ptr(stack, far, sz),
+            Dest::Here(stack, sz) => Src::Here(stack, sz),
+            Dest::At(addr, sz) => Src::At(addr, sz),
+            Dest::Global(global, offset, sz) => Src::Global(global, offset, sz),
+        }
+    }
+
     pub(crate) fn offset(self, amt: i32) -> Dest {
         match self {
             Dest::Nowhere => Dest::Nowhere,
             Dest::Ptr(stack, far, sz) => Dest::Ptr(stack, far + amt, sz),
-            Dest::Here(stack, sz) => Dest::Here(stack + amt, sz)
+            Dest::Here(stack, sz) => Dest::Here(stack + amt, sz),
+            Dest::At(addr, sz) => Dest::At(addr.offset(amt), sz),
+            Dest::Global(global, offset, sz) => Dest::Global(global, offset + amt, sz),
         }
     }
 }
@@ -69,7 +222,13 @@ pub(crate) fn same_size(dest: Dest, src: Src) -> bool {
         (Dest::Nowhere, _) => true,
         (_, Src::Uninitialized) => true,
         (_, Src::Imm(_)) => true,
-        (Dest::Ptr(_, _, sz1) | Dest::Here(_, sz1), Src::Ptr(_, _, sz2) | Src::Here(_, sz2)) => sz1 == sz2,
+        (_, Src::LabelAddr(_)) => true,
+        (_, Src::AddrOf(_)) => true,
+        (_, Src::DataAddr(_)) => true,
+        (
+            Dest::Ptr(_, _, sz1) | Dest::Here(_, sz1) | Dest::At(_, sz1) | Dest::Global(_, _, sz1), 
+            Src::Ptr(_, _, sz2) | Src::Here(_, sz2) | Src::At(_, sz2) | Src::Global(_, _, sz2)
+        ) => sz1 == sz2,
         
     }
 
@@ -0,0 +1,191 @@
+use std::sync::atomic::{self, AtomicU8, AtomicU16, AtomicU32, AtomicU64};
+
+use crate::instruction::{Dest, Src, Size, Addr, Ordering};
+use super::{InterpreterFn, InterpError, Problem, load, store, base_of, writable};
+
+// what an atomic instruction does to its place
+#[derive(Clone, Copy)]
+pub(super) enum Op { Load, Store(u64), FetchAdd(u64), Swap(u64), CompareExchange(u64, u64) }
+
+// does `op` to `place` and returns what was there before (except for a Store, which doesn't read it).
+// in host mode, FFI code on another thread could be using the same memory, so it has to be a real atomic
+// (and so `place` has to be aligned). otherwise nothing else can see `memory`, and loads and stores are enough
+pub(super) fn atomic(interp: &InterpreterFn, stack: &mut Vec<u8>, bp: usize, ip: usize, place: Dest, op: Op, ordering: Ordering) -> Result<u64, InterpError> {
+    let sz = place.size().ok_or_else(|| interp.invalid(ip, Problem::NotInMemory))?;
+    // only the bytes that fit in the place get compared
+    let mask = u64::MAX >> (64 - 8 * sz.n_bytes());
+
+    if !interp.host_memory {
+        if let Op::Store(value) = op {
+            store(interp, stack, bp, ip, place, value)?;
+            return Ok(0)
+        }
+        let prev = load(interp, stack, bp, ip, place.as_src())?;
+        let next = match op {
+            Op::Load | Op::Store(_) => None,
+            Op::Swap(value) => Some(value),
+            Op::FetchAdd(value) => Some(prev.wrapping_add(value)),
+            Op::CompareExchange(expected, new) => if prev == expected & mask { Some(new) } else { None },
+        };
+        if let Some(next) = next {
+            store(interp, stack, bp, ip, place, next)?;
+        }
+        return Ok(prev)
+    }
+
+    let address = match place {
+        Dest::Nowhere => unreachable!("it has a size"),
+        Dest::Here(stack_offset, _) => bp.wrapping_add(stack_offset as usize),
+        Dest::Global(global, offset, _) => (interp.origin(stack) + interp.global_location(ip, global)?).wrapping_add(offset as usize),
+        Dest::Ptr(offset_to_ptr, offset_after_ptr, _) => {
+            let addr = Addr::ptr(offset_to_ptr, offset_after_ptr);
+            base_of(interp, stack, bp, ip, addr)?.wrapping_add(addr.disp as usize)
+        }
+        Dest::At(addr, _) => base_of(interp, stack, bp, ip, addr)?.wrapping_add(addr.disp as usize),
+    };
+    if address % sz.n_bytes() as usize != 0 {
+        return Err(interp.invalid(ip, Problem::Misaligned(address)));
+    }
+    if !matches!(op, Op::Load) {
+        writable(interp, ip, stack, address, sz)?;
+    }
+
+    let (success, failure) = orderings(ordering);
+    macro_rules! on {
+        ($atomic:ty, $int:ty) => {{
+            // safe as far as host mode ever is: the address is aligned, and the code says it's a place
+            let atomic = unsafe { &*(address as *const $atomic) };
+            match op {
+                Op::Load => atomic.load(success) as u64,
+                Op::Store(value) => { atomic.store(value as $int, success); 0 }
+                Op::FetchAdd(value) => atomic.fetch_add(value as $int, success) as u64,
+                Op::Swap(value) => atomic.swap(value as $int, success) as u64,
+                Op::CompareExchange(expected, new) => match atomic.compare_exchange(expected as $int, new as $int, success, failure) {
+                    Ok(prev) | Err(prev) => prev as u64,
+                },
+            }
+        }};
+    }
+    let prev = match sz {
+        Size::B => on!(AtomicU8, u8),
+        Size::H => on!(AtomicU16, u16),
+        Size::D => on!(AtomicU32, u32),
+        Size::Q => on!(AtomicU64, u64),
+    };
+
+    if !matches!(op, Op::Store(_)) {
+        interp.note_load(place.as_src(), prev);
+    }
+    match op {
+        Op::Store(value) | Op::Swap(value) => interp.note_store(place, value),
+        Op::FetchAdd(value) => interp.note_store(place, prev.wrapping_add(value)),
+        Op::CompareExchange(expected, new) if prev == expected & mask => interp.note_store(place, new),
+        _ => {}
+    }
+    Ok(prev)
+}
+
+// the place an AtomicLoad reads from, if it's in memory (it can load an immediate too, like codegen)
+pub(super) fn place_of(src: Src) -> Option<Dest> {
+    match src {
+        Src::Ptr(offset_to_ptr, offset_after_ptr, sz) => Some(Dest::Ptr(offset_to_ptr, offset_after_ptr, sz)),
+        Src::Here(stack_offset, sz) => Some(Dest::Here(stack_offset, sz)),
+        Src::At(addr, sz) => Some(Dest::At(addr, sz)),
+        Src::Global(global, offset, sz) => Some(Dest::Global(global, offset, sz)),
+        _ => None,
+    }
+}
+
+// for the read-modify-writes, the ordering on failure can't be stronger than a load
+fn orderings(ordering: Ordering) -> (atomic::Ordering, atomic::Ordering) {
+    match ordering {
+        Ordering::Relaxed => (atomic::Ordering::Relaxed, atomic::Ordering::Relaxed),
+        Ordering::Release => (atomic::Ordering::Release, atomic::Ordering::Relaxed),
+        Ordering::Acquire => (atomic::Ordering::Acquire, atomic::Ordering::Acquire),
+        Ordering::AcqRel => (atomic::Ordering::AcqRel, atomic::Ordering::Acquire),
+        Ordering::SeqCst => (atomic::Ordering::SeqCst, atomic::Ordering::SeqCst),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, atomic::AtomicU64};
+
+    use crate::instruction::{Instruction, Label, Signedness};
+    use crate::interpreter_fn::test_util::{N, interpreter};
+    use super::*;
+
+    fn host(instructions: Vec<Instruction>) -> InterpreterFn {
+        let mut interp = interpreter(instructions);
+        unsafe { interp.use_host_memory() };
+        interp
+    }
+
+    #[test]
+    fn host_atomics_are_atomic() {
+        // arg0 points to a counter, which gets arg1 increments
+        let interp = host(vec![
+            Instruction::FFIBegin(16, [Dest::Here(-8, Size::Q), Dest::Here(-16, Size::Q), N, N, N, N]),
+            Instruction::Label(Label(0)),
+            Instruction::SubChecked(Dest::Here(-16, Size::Q), Src::Here(-16, Size::Q), Src::Imm(1), Signedness::Unsigned, Label(1)),
+            Instruction::FetchAdd(N, Dest::Ptr(-8, 0, Size::Q), Src::Imm(1), Ordering::Relaxed),
+            Instruction::JIf(Src::Imm(1), Label(0)),
+            Instruction::Label(Label(1)),
+            Instruction::FFIRet(Src::Imm(0)),
+        ]);
+
+        let counter = Arc::new(AtomicU64::new(0));
+        let other = {
+            let counter = counter.clone();
+            std::thread::spawn(move || for _ in 0..100_000 { counter.fetch_add(1, atomic::Ordering::Relaxed); })
+        };
+        interp.run(counter.as_ptr() as u64, 100_000, 0, 0, 0, 0).unwrap();
+        other.join().unwrap();
+        assert_eq!(counter.load(atomic::Ordering::Relaxed), 200_000);
+    }
+
+    #[test]
+    fn host_atomics() {
+        let cell = AtomicU64::new(0x1122_3344_5566_7788);
+        let args = |interp: &InterpreterFn| interp.run(cell.as_ptr() as u64, 0, 0, 0, 0, 0);
+        let begin = Instruction::FFIBegin(16, [Dest::Here(-8, Size::Q), N, N, N, N, N]);
+
+        // the low byte is 0x88, so only an expected 0x88 (or anything ending in it) matches
+        let compare_exchange = |expected| host(vec![
+            begin.clone(),
+            Instruction::CompareExchange(Dest::Here(-16, Size::Q), Dest::Ptr(-8, 0, Size::B), Src::Imm(expected), Src::Imm(0x99), Ordering::SeqCst),
+            Instruction::FFIRet(Src::Here(-16, Size::Q)),
+        ]);
+        assert_eq!(args(&compare_exchange(0x77)).unwrap(), 0x88);
+        assert_eq!(cell.load(atomic::Ordering::SeqCst), 0x1122_3344_5566_7788);
+        assert_eq!(args(&compare_exchange(0xff88)).unwrap(), 0x88);
+        assert_eq!(cell.load(atomic::Ordering::SeqCst), 0x1122_3344_5566_7799);
+
+        let swap = host(vec![
+            begin.clone(),
+            Instruction::Swap(Dest::Here(-16, Size::Q), Dest::Ptr(-8, 4, Size::D), Src::Imm(0xaabb_ccdd), Ordering::AcqRel),
+            Instruction::AtomicLoad(Dest::Here(-16, Size::Q), Src::Ptr(-8, 0, Size::Q), Ordering::Acquire),
+            Instruction::FFIRet(Src::Here(-16, Size::Q)),
+        ]);
+        assert_eq!(args(&swap).unwrap(), 0xaabb_ccdd_5566_7799);
+
+        let store = host(vec![
+            begin.clone(),
+            Instruction::AtomicStore(Dest::Ptr(-8, 0, Size::H), Src::Imm(0x1_0203), Ordering::Release),
+            Instruction::FFIRet(Src::Imm(0)),
+        ]);
+        args(&store).unwrap();
+        assert_eq!(cell.load(atomic::Ordering::SeqCst), 0xaabb_ccdd_5566_0203);
+
+        // real atomics have to be aligned
+        let misaligned = host(vec![
+            begin,
+            Instruction::FetchAdd(N, Dest::Ptr(-8, 2, Size::D), Src::Imm(1), Ordering::SeqCst),
+            Instruction::FFIRet(Src::Imm(0)),
+        ]);
+        match args(&misaligned) {
+            Err(InterpError::Invalid { ip: 1, problem: Problem::Misaligned(address), .. }) => assert_eq!(address, cell.as_ptr() as usize + 2),
+            result => panic!("{:?}", result),
+        }
+    }
+}
@@ -0,0 +1,489 @@
+use std::{cell::RefCell, ops::Range};
+
+use crate::instruction::{Label, Instruction, same_size, Dest, Src, Size, Addr, GlobalId, Signedness};
+use crate::trap::{Trap, TrapCode};
+use crate::object;
+use super::{InterpreterFn, InterpError, Problem, LABEL_ADDR_TAG};
+
+// InterpreterFn with the decoding done up front: every instruction becomes a closure, with its labels
+// resolved to indexes and its Here/Global slots resolved to places in `memory` (bp never changes during a run).
+// it behaves the same as InterpreterFn::run, but only in the default mode -- no sandbox, host memory, checking, tracing or mocks
+// (compiling in any other mode is a CompileError)
+pub struct CompiledFn {
+    code: Vec<Instruction>,  // just for errors
+    ops: Vec<Op>,
+
+    global_locations: Vec<Range<usize>>,
+    globals_start: usize,
+    memory_template: RefCell<Vec<u8>>,
+    fuel: u64,
+}
+
+struct Machine {
+    memory: Vec<u8>,  // [stack | data | globals], like InterpreterFn's
+    args: [u64; 6],
+}
+
+// what to run next
+enum Flow { Next, Jump(usize), Return(u64) }
+
+// why an op couldn't finish. the index and instruction get filled in by `execute`
+enum Stop { Invalid(Problem), Trap(TrapCode) }
+
+impl From<Problem> for Stop {
+    fn from(problem: Problem) -> Self {
+        Stop::Invalid(problem)
+    }
+}
+
+// why an InterpreterFn couldn't be compiled: it's in a mode CompiledFn doesn't have
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CompileError {
+    Sandboxed,
+    HostMemory,
+    Checked,
+    Mocked,
+    Traced,
+    Profiled,
+}
+
+type Op = Box<dyn Fn(&mut Machine) -> Result<Flow, Stop>>;
+type Read = Box<dyn Fn(&[u8]) -> Result<u64, Problem>>;
+type Write = Box<dyn Fn(&mut [u8], u64) -> Result<(), Problem>>;
+// the same, for the place `i` bytes along (the same as Src::offset and Dest::offset)
+type ReadAlong = Box<dyn Fn(&[u8], u64) -> Result<u64, Problem>>;
+type WriteAlong = Box<dyn Fn(&mut [u8], u64, u64) -> Result<(), Problem>>;
+
+impl InterpreterFn {
+    pub fn compile(&self) -> Result<CompiledFn, CompileError> {
+        let unsupported = [
+            (self.linear_memory.is_some(), CompileError::Sandboxed),
+            (self.host_memory, CompileError::HostMemory),
+            (self.checked, CompileError::Checked),
+            (!self.mocks.borrow().is_empty(), CompileError::Mocked),
+            (self.tracer.borrow().is_some(), CompileError::Traced),
+            (self.counts.is_some(), CompileError::Profiled),
+        ];
+        if let Some((_, error)) = unsupported.into_iter().find(|(on, _)| *on) {
+            return Err(error);
+        }
+
+        let ops = self.code.iter().map(|instruction| self.compile_op(instruction)).collect();
+        Ok(CompiledFn {
+            code: self.code.clone(), ops,
+            global_locations: self.global_locations.clone(), globals_start: self.globals_start,
+            memory_template: RefCell::new(self.memory_template.borrow().clone()),
+            fuel: self.fuel,
+        })
+    }
+
+    fn compile_op(&self, instruction: &Instruction) -> Op {
+        let fail = |problem: Problem| -> Op { Box::new(move |_| Err(problem.into())) };
+
+        match *instruction {
+            Instruction::FFIBegin(n_bytes, dests) => {
+                if n_bytes > self.stack_size as u64 { return fail(Problem::FrameTooBig(n_bytes)) }
+                let writes: Vec<Write> = dests.iter().map(|dest| self.compile_write(*dest)).collect();
+                Box::new(move |machine| {
+                    for (write, arg) in writes.iter().zip(machine.args) {
+                        write(&mut machine.memory, arg)?;
+                    }
+                    Ok(Flow::Next)
+                })
+            }
+            Instruction::FFIRet(src) => {
+                let read = self.compile_read(src);
+                Box::new(move |machine| Ok(Flow::Return(read(&machine.memory)?)))
+            }
+            Instruction::Copy(dest, src, count) => {
+                if count.0 != 1 && !same_size(dest, src) { return fail(Problem::SizeMismatch) }
+                if count.0 == 1 { return self.compile_copy(dest, src) }
+                // each copy is one byte further along than the last, worked out as it goes (the count could be huge)
+                let (read, write) = (self.compile_read_along(src), self.compile_write_along(dest));
+                Box::new(move |machine| {
+                    for i in 0..count.0 {
+                        let value = read(&machine.memory, i)?;
+                        write(&mut machine.memory, i, value)?;
+                    }
+                    Ok(Flow::Next)
+                })
+            }
+            Instruction::JIf(src, label) => {
+                let (read, target) = (self.compile_read(src), self.resolve(label));
+                Box::new(move |machine| {
+                    if read(&machine.memory)? != 0 { Ok(Flow::Jump(target?)) } else { Ok(Flow::Next) }
+                })
+            }
+            Instruction::Switch(src, default, ref table) => {
+                let read = self.compile_read(src);
+                let default = self.resolve(default);
+                let table: Vec<_> = table.iter().map(|label| self.resolve(*label)).collect();
+                Box::new(move |machine| {
+                    let index = read(&machine.memory)?;
+                    let target = usize::try_from(index).ok().and_then(|i| table.get(i)).unwrap_or(&default);
+                    Ok(Flow::Jump((*target)?))
+                })
+            }
+            Instruction::JmpIndirect(src) => {
+                let read = self.compile_read(src);
+                let is_label: Vec<bool> = self.code.iter().map(|i| matches!(i, Instruction::Label(_))).collect();
+                Box::new(move |machine| {
+                    let token = read(&machine.memory)?;
+                    let index = (token ^ LABEL_ADDR_TAG) as usize;
+                    if token & LABEL_ADDR_TAG != LABEL_ADDR_TAG || !is_label.get(index).copied().unwrap_or(false) {
+                        return Err(Problem::NotALabel(token).into());
+                    }
+                    Ok(Flow::Jump(index))
+                })
+            }
+            Instruction::Label(_) => Box::new(|_| Ok(Flow::Next)),
+            Instruction::AtomicLoad(dest, src, ordering) => {
+                if !ordering.valid_for_load() { return fail(Problem::BadOrdering(ordering)) }
+                if !src.needs_load() { return fail(Problem::NotInMemory) }
+                self.compile_copy(dest, src)
+            }
+            Instruction::AtomicStore(place, src, ordering) => {
+                if !ordering.valid_for_store() { return fail(Problem::BadOrdering(ordering)) }
+                if !place.needs_store() { return fail(Problem::NotInMemory) }
+                self.compile_copy(place, src)
+            }
+            Instruction::FetchAdd(old, place, src, _) | Instruction::Swap(old, place, src, _) => {
+                if !place.needs_store() { return fail(Problem::NotInMemory) }
+                let is_add = matches!(instruction, Instruction::FetchAdd(..));
+                let (read, read_place) = (self.compile_read(src), self.compile_read(place.as_src()));
+                let (write_place, write_old) = (self.compile_write(place), self.compile_write(old));
+                Box::new(move |machine| {
+                    let value = read(&machine.memory)?;
+                    let prev = read_place(&machine.memory)?;
+                    write_place(&mut machine.memory, if is_add { prev.wrapping_add(value) } else { value })?;
+                    write_old(&mut machine.memory, prev)?;
+                    Ok(Flow::Next)
+                })
+            }
+            Instruction::CompareExchange(old, place, expected, new, _) => {
+                if !place.needs_store() { return fail(Problem::NotInMemory) }
+                let (read_new, read_expected) = (self.compile_read(new), self.compile_read(expected));
+                let read_place = self.compile_read(place.as_src());
+                let (write_place, write_old) = (self.compile_write(place), self.compile_write(old));
+                // only the bytes that fit in the place get compared
+                let mask = u64::MAX >> (64 - 8 * place.size().expect("checked above").n_bytes());
+                Box::new(move |machine| {
+                    let new = read_new(&machine.memory)?;
+                    let expected = read_expected(&machine.memory)?;
+                    let prev = read_place(&machine.memory)?;
+                    if prev == expected & mask {
+                        write_place(&mut machine.memory, new)?;
+                    }
+                    write_old(&mut machine.memory, prev)?;
+                    Ok(Flow::Next)
+                })
+            }
+            Instruction::Fence(ordering) => {
+                if !ordering.valid_for_fence() { return fail(Problem::BadOrdering(ordering)) }
+                Box::new(|_| Ok(Flow::Next))
+            }
+            Instruction::AddChecked(dest, a, b, signedness, overflow) |
+            Instruction::SubChecked(dest, a, b, signedness, overflow) |
+            Instruction::MulChecked(dest, a, b, signedness, overflow) => {
+                let operation: fn(u64, u64) -> Option<u64> = match (instruction, signedness) {
+                    (Instruction::AddChecked(..), Signedness::Unsigned) => |a, b| a.checked_add(b),
+                    (Instruction::SubChecked(..), Signedness::Unsigned) => |a, b| a.checked_sub(b),
+                    (Instruction::MulChecked(..), Signedness::Unsigned) => |a, b| a.checked_mul(b),
+                    (Instruction::AddChecked(..), Signedness::Signed) => |a, b| (a as i64).checked_add(b as i64).map(|x| x as u64),
+                    (Instruction::SubChecked(..), Signedness::Signed) => |a, b| (a as i64).checked_sub(b as i64).map(|x| x as u64),
+                    (Instruction::MulChecked(..), Signedness::Signed) => |a, b| (a as i64).checked_mul(b as i64).map(|x| x as u64),
+                    _ => unreachable!(),
+                };
+                let (read_a, read_b, write) = (self.compile_read(a), self.compile_read(b), self.compile_write(dest));
+                let overflow = self.resolve(overflow);
+                Box::new(move |machine| {
+                    let a = read_a(&machine.memory)?;
+                    let b = read_b(&machine.memory)?;
+                    match operation(a, b) {
+                        Some(result) => { write(&mut machine.memory, result)?; Ok(Flow::Next) }
+                        None => Ok(Flow::Jump(overflow?)),
+                    }
+                })
+            }
+            Instruction::Trap(code) => Box::new(move |_| Err(Stop::Trap(TrapCode::User(code)))),
+            Instruction::FFICall(dest, args, function) => {
+                let reads: Vec<Read> = args.iter().map(|arg| self.compile_read(*arg)).collect();
+                let write = self.compile_write(dest);
+                Box::new(move |machine| {
+                    let mut values = [0; 6];
+                    for (value, read) in values.iter_mut().zip(reads.iter()) {
+                        *value = read(&machine.memory)?;
+                    }
+                    let result = (function.pointer)(values[0], values[1], values[2], values[3], values[4], values[5]);
+                    write(&mut machine.memory, result)?;
+                    Ok(Flow::Next)
+                })
+            }
+        }
+    }
+
+    fn compile_copy(&self, dest: Dest, src: Src) -> Op {
+        let (read, write) = (self.compile_read(src), self.compile_write(dest));
+        Box::new(move |machine| {
+            let value = read(&machine.memory)?;
+            write(&mut machine.memory, value)?;
+            Ok(Flow::Next)
+        })
+    }
+
+    // undefined labels are only a problem if they're jumped to
+    fn resolve(&self, label: Label) -> Result<usize, Problem> {
+        self.label_locations.get(&label).copied().ok_or(Problem::UndefinedLabel(label))
+    }
+
+    fn compile_read(&self, src: Src) -> Read {
+        let bp = self.stack_size;
+        let constant = |value: Result<u64, Problem>| -> Read { Box::new(move |_| value) };
+
+        match src {
+            Src::Uninitialized => constant(Ok(0x123456789abcdef0)),
+            Src::Imm(i) => constant(Ok(i)),
+            Src::AddrOf(stack_offset) => constant(Ok((bp as i64 + stack_offset as i64) as u64)),
+            Src::DataAddr(data) => constant(self.data_locations.get(data.0).map(|l| *l as u64).ok_or(Problem::UndefinedData(data))),
+            Src::LabelAddr(label) => constant(self.resolve(label).map(|l| LABEL_ADDR_TAG | l as u64)),
+            Src::Here(stack_offset, sz) => {
+                let location = bp.wrapping_add(stack_offset as usize);
+                Box::new(move |memory| read_at(memory, location, sz))
+            }
+            Src::Global(global, offset, sz) => match self.global_locations.get(global.0) {
+                Some(range) => {
+                    let location = range.start.wrapping_add(offset as usize);
+                    Box::new(move |memory| read_at(memory, location, sz))
+                }
+                None => constant(Err(Problem::UndefinedGlobal(global))),
+            },
+            Src::Ptr(offset_to_ptr, offset_after_ptr, sz) => {
+                let addr = Addr::ptr(offset_to_ptr, offset_after_ptr);
+                Box::new(move |memory| read_at(memory, base_of(memory, bp, addr)?.wrapping_add(addr.disp as usize), sz))
+            }
+            Src::At(addr, sz) => Box::new(move |memory| read_at(memory, base_of(memory, bp, addr)?.wrapping_add(addr.disp as usize), sz)),
+        }
+    }
+
+    fn compile_write(&self, dest: Dest) -> Write {
+        let bp = self.stack_size;
+        let data = self.stack_size..self.globals_start;
+
+        match dest {
+            Dest::Nowhere => Box::new(|_, _| Ok(())),
+            Dest::Here(stack_offset, sz) => {
+                let location = bp.wrapping_add(stack_offset as usize);
+                Box::new(move |memory, value| write_at(memory, &data, location, sz, value))
+            }
+            Dest::Global(global, offset, sz) => match self.global_locations.get(global.0) {
+                Some(range) => {
+                    let location = range.start.wrapping_add(offset as usize);
+                    Box::new(move |memory, value| write_at(memory, &data, location, sz, value))
+                }
+                None => Box::new(move |_, _| Err(Problem::UndefinedGlobal(global))),
+            },
+            Dest::Ptr(offset_to_ptr, offset_after_ptr, sz) => {
+                let addr = Addr::ptr(offset_to_ptr, offset_after_ptr);
+                Box::new(move |memory, value| write_at(memory, &data, base_of(memory, bp, addr)?.wrapping_add(addr.disp as usize), sz, value))
+            }
+            Dest::At(addr, sz) => Box::new(move |memory, value| write_at(memory, &data, base_of(memory, bp, addr)?.wrapping_add(addr.disp as usize), sz, value)),
+        }
+    }
+
+    fn compile_read_along(&self, src: Src) -> ReadAlong {
+        let bp = self.stack_size;
+
+        match src {
+            Src::Here(stack_offset, sz) => {
+                let location = bp.wrapping_add(stack_offset as usize);
+                Box::new(move |memory, i| read_at(memory, along(location, i), sz))
+            }
+            Src::Global(global, offset, sz) => match self.global_locations.get(global.0) {
+                Some(range) => {
+                    let location = range.start.wrapping_add(offset as usize);
+                    Box::new(move |memory, i| read_at(memory, along(location, i), sz))
+                }
+                None => Box::new(move |_, _| Err(Problem::UndefinedGlobal(global))),
+            },
+            Src::Ptr(offset_to_ptr, offset_after_ptr, sz) => {
+                let addr = Addr::ptr(offset_to_ptr, offset_after_ptr);
+                Box::new(move |memory, i| read_at(memory, along(base_of(memory, bp, addr)?.wrapping_add(addr.disp as usize), i), sz))
+            }
+            Src::At(addr, sz) => Box::new(move |memory, i| read_at(memory, along(base_of(memory, bp, addr)?.wrapping_add(addr.disp as usize), i), sz)),
+            // the rest aren't places, so they're the same all the way along
+            _ => {
+                let read = self.compile_read(src);
+                Box::new(move |memory, _| read(memory))
+            }
+        }
+    }
+
+    fn compile_write_along(&self, dest: Dest) -> WriteAlong {
+        let bp = self.stack_size;
+        let data = self.stack_size..self.globals_start;
+
+        match dest {
+            Dest::Nowhere => Box::new(|_, _, _| Ok(())),
+            Dest::Here(stack_offset, sz) => {
+                let location = bp.wrapping_add(stack_offset as usize);
+                Box::new(move |memory, i, value| write_at(memory, &data, along(location, i), sz, value))
+            }
+            Dest::Global(global, offset, sz) => match self.global_locations.get(global.0) {
+                Some(range) => {
+                    let location = range.start.wrapping_add(offset as usize);
+                    Box::new(move |memory, i, value| write_at(memory, &data, along(location, i), sz, value))
+                }
+                None => Box::new(move |_, _, _| Err(Problem::UndefinedGlobal(global))),
+            },
+            Dest::Ptr(offset_to_ptr, offset_after_ptr, sz) => {
+                let addr = Addr::ptr(offset_to_ptr, offset_after_ptr);
+                Box::new(move |memory, i, value| write_at(memory, &data, along(base_of(memory, bp, addr)?.wrapping_add(addr.disp as usize), i), sz, value))
+            }
+            Dest::At(addr, sz) => Box::new(move |memory, i, value| write_at(memory, &data, along(base_of(memory, bp, addr)?.wrapping_add(addr.disp as usize), i), sz, value)),
+        }
+    }
+}
+
+// `i` bytes along from `location`
+fn along(location: usize, i: u64) -> usize {
+    location.wrapping_add(i as i32 as usize)
+}
+
+impl CompiledFn {
+    // instructions, the same as InterpreterFn::set_fuel
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = fuel;
+    }
+
+    // separately from the InterpreterFn this came from
+    pub fn read_global(&self, global: GlobalId) -> Vec<u8> {
+        object::read_global(&self.global_locations, &self.memory_template.borrow(), global)
+    }
+
+    pub fn write_global(&self, global: GlobalId, bytes: &[u8]) {
+        object::write_global(&self.global_locations, &mut self.memory_template.borrow_mut(), global, bytes)
+    }
+
+    pub fn run(&self, arg0: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> Result<u64, InterpError> {
+        let mut machine = Machine { memory: self.memory_template.borrow().clone(), args: [arg0, arg1, arg2, arg3, arg4, arg5] };
+        let result = self.execute(&mut machine);
+        self.memory_template.borrow_mut()[self.globals_start..].clone_from_slice(&machine.memory[self.globals_start..]);
+        result
+    }
+
+    fn execute(&self, machine: &mut Machine) -> Result<u64, InterpError> {
+        let mut ip = 0;
+        let mut fuel = self.fuel;
+        loop {
+            let op = self.ops.get(ip).ok_or(InterpError::Invalid { ip, instruction: None, problem: Problem::IpEscaped })?;
+
+            if fuel == 0 {
+                return Err(Trap { code: TrapCode::OutOfFuel, ir_index: ip }.into());
+            }
+            fuel -= 1;
+
+            match op(machine) {
+                Ok(Flow::Next) => ip += 1,
+                Ok(Flow::Jump(target)) => ip = target,
+                Ok(Flow::Return(value)) => return Ok(value),
+                Err(Stop::Invalid(problem)) => return Err(InterpError::Invalid { ip, instruction: Some(Box::new(self.code[ip].clone())), problem }),
+                Err(Stop::Trap(code)) => return Err(Trap { code, ir_index: ip }.into()),
+            }
+        }
+    }
+}
+
+fn read_at(memory: &[u8], location: usize, size: Size) -> Result<u64, Problem> {
+    let out_of_range = Problem::OutOfRange(location);
+    Ok(match size {
+        Size::B => *memory.get(location).ok_or(out_of_range)? as u64,
+        Size::H => u16::from_le_bytes(bytes(memory, location).ok_or(out_of_range)?) as u64,
+        Size::D => u32::from_le_bytes(bytes(memory, location).ok_or(out_of_range)?) as u64,
+        Size::Q => u64::from_le_bytes(bytes(memory, location).ok_or(out_of_range)?),
+    })
+}
+
+// the data is read-only
+fn write_at(memory: &mut [u8], data: &Range<usize>, location: usize, size: Size, value: u64) -> Result<(), Problem> {
+    let n_bytes = size.n_bytes() as usize;
+    if location < data.end && location.wrapping_add(n_bytes) > data.start {
+        return Err(Problem::ReadOnly(location));
+    }
+    let end = location.checked_add(n_bytes).ok_or(Problem::OutOfRange(location))?;
+    let place = memory.get_mut(location..end).ok_or(Problem::OutOfRange(location))?;
+    place.clone_from_slice(&value.to_le_bytes()[..n_bytes]);
+    Ok(())
+}
+
+fn bytes<const N: usize>(memory: &[u8], location: usize) -> Option<[u8; N]> {
+    memory.get(location..location.checked_add(N)?)?.try_into().ok()
+}
+
+// everything but the displacement
+fn base_of(memory: &[u8], bp: usize, addr: Addr) -> Result<usize, Problem> {
+    let mut base = bp;
+    for offset in addr.chain.offsets() {
+        base = read_at(memory, base.wrapping_add(*offset as usize), Size::Q)? as usize;
+    }
+    if let Some((index_offset, scale)) = addr.index {
+        let index = read_at(memory, bp.wrapping_add(index_offset as usize), Size::Q)?;
+        base = base.wrapping_add(index.wrapping_mul(scale.n_bytes()) as usize);
+    }
+    Ok(base)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::Count;
+    use crate::object::Object;
+    use super::*;
+
+    #[test]
+    fn huge_copies_compile() {
+        let object = || Object { data: vec![], globals: vec![], instructions: vec![
+            Instruction::FFIBegin(8, [Dest::Nowhere; 6]),
+            Instruction::Copy(Dest::Here(-8, Size::B), Src::Imm(0), Count(1 << 40)),
+            Instruction::FFIRet(Src::Imm(0)),
+        ]};
+        // it runs off the end of the stack long before it gets anywhere near the count
+        let compiled = InterpreterFn::new(object(), 1024).compile().unwrap();
+        let reference = InterpreterFn::new(object(), 1024);
+        for result in [compiled.run(0, 0, 0, 0, 0, 0), reference.run(0, 0, 0, 0, 0, 0)] {
+            match result {
+                Err(InterpError::Invalid { ip: 1, problem: Problem::OutOfRange(1024), .. }) => {}
+                result => panic!("{:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn only_the_default_mode_compiles() {
+        let interp = || InterpreterFn::new(Object { data: vec![], globals: vec![], instructions: vec![] }, 1024);
+        assert!(interp().compile().is_ok());
+
+        let mut sandboxed = interp();
+        sandboxed.set_memory(vec![0; 16]);
+        let mut host = interp();
+        unsafe { host.use_host_memory() };
+        let mut checked = interp();
+        checked.set_checked(true);
+        let mut mocked = interp();
+        mocked.mock("f", |_| 0);
+        let mut traced = interp();
+        traced.set_trace(|_| {});
+        let mut profiled = interp();
+        profiled.set_profiling(true);
+        for (interp, error) in [
+            (sandboxed, CompileError::Sandboxed), (host, CompileError::HostMemory), (checked, CompileError::Checked),
+            (mocked, CompileError::Mocked), (traced, CompileError::Traced), (profiled, CompileError::Profiled),
+        ] {
+            assert_eq!(interp.compile().err(), Some(error));
+        }
+
+        // and once it's back in the default mode, it does
+        let mut was_profiled = interp();
+        was_profiled.set_profiling(true);
+        was_profiled.set_profiling(false);
+        assert!(was_profiled.compile().is_ok());
+    }
+}
@@ -0,0 +1,46 @@
+use crate::instruction::{Label, Instruction, DataId, GlobalId, Ordering};
+use crate::trap::Trap;
+use super::SnapshotError;
+
+// why InterpreterFn::run didn't return a value
+#[derive(Clone, Debug)]
+pub enum InterpError {
+    // the code stopped itself, the same way it would have in the JIT
+    Trap(Trap),
+
+    // the code is wrong: codegen would have rejected it, or the JIT would have done something undefined
+    Invalid {
+        ip: usize,
+        instruction: Option<Box<Instruction>>,  // None if ip isn't in the code at all (boxed, to keep results small)
+        problem: Problem,
+    },
+
+    // a run couldn't be paused, or the snapshot it was resumed from isn't of this code in this mode
+    Snapshot(SnapshotError),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Problem {
+    IpEscaped,  // ran off the end of the code
+    UndefinedLabel(Label),
+    UndefinedData(DataId),
+    UndefinedGlobal(GlobalId),
+    OutOfRange(usize),  // an index outside the interpreter's memory
+    ReadOnly(usize),  // a store to the data (an index in memory, or an address in host mode)
+    FrameTooBig(u64),  // bigger than the whole stack
+    SizeMismatch,  // a Copy of more than one thing, between places of different sizes
+    NotALabel(u64),  // what JmpIndirect was given instead of a LabelAddr
+    NotInSandbox,  // JmpIndirect, which a sandbox doesn't allow
+    BadOrdering(Ordering),
+    NotInMemory,  // an atomic on something that isn't a place in memory
+    Misaligned(usize),  // in host mode, an atomic on an address that isn't a multiple of its size
+    Uninitialized(usize),  // in checked mode, a read of a byte (this index in memory) that was never written
+    MockRanOut,  // a mock_returns was called more times than it had values for
+    MockReentered,  // a mock ended up calling itself, by running the interpreter again
+}
+
+impl From<Trap> for InterpError {
+    fn from(trap: Trap) -> Self {
+        InterpError::Trap(trap)
+    }
+}
@@ -0,0 +1,157 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::instruction::FFIFunction;
+use super::{InterpreterFn, InterpError, Problem};
+
+// None if it's run out of things to return
+type Behaviour = Rc<RefCell<dyn FnMut([u64; 6]) -> Option<u64>>>;
+
+// stands in for an FFIFunction with the same name, and remembers how it was called.
+// the behaviour is shared so it can run without the map of mocks borrowed (it might run the interpreter again)
+pub(super) struct Mock {
+    behaviour: Behaviour,
+    calls: Vec<[u64; 6]>,
+}
+
+impl InterpreterFn {
+    // from now on, FFICalls to functions called `name` call `behaviour` instead
+    pub fn mock(&mut self, name: &str, mut behaviour: impl FnMut([u64; 6]) -> u64 + 'static) {
+        self.mock_with(name, move |args| Some(behaviour(args)));
+    }
+
+    // a mock that returns `values` in order. a call after that is a Problem::MockRanOut
+    pub fn mock_returns(&mut self, name: &str, values: Vec<u64>) {
+        let mut values = values.into_iter();
+        self.mock_with(name, move |_| values.next());
+    }
+
+    fn mock_with(&mut self, name: &str, behaviour: impl FnMut([u64; 6]) -> Option<u64> + 'static) {
+        self.mocks.get_mut().insert(name.to_string(), Mock { behaviour: Rc::new(RefCell::new(behaviour)), calls: vec![] });
+    }
+
+    pub fn clear_mocks(&mut self) {
+        self.mocks.get_mut().clear();
+    }
+
+    // the args of every call to the mock called `name` so far, oldest first
+    pub fn calls(&self, name: &str) -> Vec<[u64; 6]> {
+        self.mocks.borrow().get(name).unwrap_or_else(|| panic!("{} isn't mocked", name)).calls.clone()
+    }
+
+    pub(super) fn call(&self, ip: usize, function: FFIFunction, args: [u64; 6]) -> Result<u64, InterpError> {
+        let behaviour = match self.mocks.borrow_mut().get_mut(function.name) {
+            Some(mock) => {
+                mock.calls.push(args);
+                mock.behaviour.clone()
+            }
+            None => return Ok((function.pointer)(args[0], args[1], args[2], args[3], args[4], args[5])),
+        };
+        // a mock that (through another run) ends up calling itself can't be borrowed twice
+        let mut behaviour = behaviour.try_borrow_mut().map_err(|_| self.invalid(ip, Problem::MockReentered))?;
+        behaviour(args).ok_or_else(|| self.invalid(ip, Problem::MockRanOut))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, rc::Rc};
+
+    use crate::instruction::{Instruction, Dest, Src, Size, Signedness, Label};
+    use crate::interpreter_fn::test_util::{N, interpreter, invalid};
+    use super::*;
+
+    extern "C" fn add(a: u64, b: u64, _: u64, _: u64, _: u64, _: u64) -> u64 { a + b }
+
+    // add(arg0, arg1) twice, and the sum of the results
+    fn adds() -> InterpreterFn {
+        interpreter(vec![
+            Instruction::FFIBegin(32, [Dest::Here(-8, Size::Q), Dest::Here(-16, Size::Q), N, N, N, N]),
+            Instruction::FFICall(Dest::Here(-24, Size::Q), [Src::Here(-8, Size::Q), Src::Here(-16, Size::Q), Src::Imm(0), Src::Imm(0), Src::Imm(0), Src::Imm(0)], FFIFunction::new("add", add)),
+            Instruction::FFICall(Dest::Here(-32, Size::Q), [Src::Here(-16, Size::Q), Src::Imm(7), Src::Imm(0), Src::Imm(0), Src::Imm(0), Src::Imm(0)], FFIFunction::new("add", add)),
+            Instruction::AddChecked(Dest::Here(-24, Size::Q), Src::Here(-24, Size::Q), Src::Here(-32, Size::Q), Signedness::Unsigned, Label(0)),
+            Instruction::Label(Label(0)),
+            Instruction::FFIRet(Src::Here(-24, Size::Q)),
+        ])
+    }
+
+    #[test]
+    fn mocks_stand_in_by_name() {
+        let mut interp = adds();
+        assert_eq!(interp.run(1, 2, 0, 0, 0, 0).unwrap(), 3 + 9);
+
+        interp.mock("add", |args| args[0] * args[1]);
+        assert_eq!(interp.run(3, 4, 0, 0, 0, 0).unwrap(), 12 + 28);
+        assert_eq!(interp.run(5, 6, 0, 0, 0, 0).unwrap(), 30 + 42);
+        assert_eq!(interp.calls("add"), vec![[3, 4, 0, 0, 0, 0], [4, 7, 0, 0, 0, 0], [5, 6, 0, 0, 0, 0], [6, 7, 0, 0, 0, 0]]);
+
+        interp.clear_mocks();
+        assert_eq!(interp.run(1, 2, 0, 0, 0, 0).unwrap(), 3 + 9);
+    }
+
+    #[test]
+    fn scripted_returns() {
+        let mut interp = adds();
+        interp.mock_returns("add", vec![100, 20, 3]);
+        assert_eq!(interp.run(0, 0, 0, 0, 0, 0).unwrap(), 120);
+        // the third call gets the last value, and the fourth has nothing left
+        assert_eq!(interp.run(0, 0, 0, 0, 0, 0).map_err(invalid), Err((2, Problem::MockRanOut)));
+        assert_eq!(interp.calls("add").len(), 4);
+    }
+
+    #[test]
+    fn mocks_can_run_the_interpreter() {
+        // the mock for `add` runs the same interpreter again, which calls the mock again
+        let interp = Rc::new(RefCell::new(None::<Rc<InterpreterFn>>));
+        let depth = Rc::new(Cell::new(0));
+        let mut mocked = adds();
+        mocked.mock("add", {
+            let (interp, depth) = (interp.clone(), depth.clone());
+            move |args| {
+                depth.set(depth.get() + 1);
+                let inner = interp.borrow().clone().unwrap();
+                match inner.run(args[0], args[1], 0, 0, 0, 0) {
+                    Err(InterpError::Invalid { problem: Problem::MockReentered, .. }) => 1,
+                    result => panic!("{:?}", result),
+                }
+            }
+        });
+        let mocked = Rc::new(mocked);
+        *interp.borrow_mut() = Some(mocked.clone());
+
+        // rather than a RefCell panic, the inner call is invalid, and the map of mocks was free to record it
+        assert_eq!(mocked.run(1, 2, 0, 0, 0, 0).unwrap(), 2);
+        assert_eq!(depth.get(), 2);
+        assert_eq!(mocked.calls("add").len(), 4);
+        interp.borrow_mut().take();
+    }
+
+    #[test]
+    fn traced_while_a_mock_runs_the_interpreter() {
+        // the inner run's instructions finish first, and the FFICall's entry still gets what it stored
+        let interp = Rc::new(RefCell::new(None::<Rc<InterpreterFn>>));
+        let entries = Rc::new(RefCell::new(vec![]));
+        let mut mocked = adds();
+        mocked.mock("add", {
+            let interp = interp.clone();
+            move |args| {
+                let inner = interp.borrow().clone().unwrap();
+                assert!(inner.run(args[0], args[1], 0, 0, 0, 0).is_err());
+                5
+            }
+        });
+        mocked.set_trace({
+            let entries = entries.clone();
+            move |entry: &crate::interpreter_fn::TraceEntry| entries.borrow_mut().push((entry.ip, entry.stores.clone()))
+        });
+        let mocked = Rc::new(mocked);
+        *interp.borrow_mut() = Some(mocked.clone());
+
+        assert_eq!(mocked.run(1, 2, 0, 0, 0, 0).unwrap(), 10);
+        let ips: Vec<usize> = entries.borrow().iter().map(|(ip, _)| *ip).collect();
+        assert_eq!(ips, [0, 0, 1, 1, 0, 1, 2, 3, 4, 5]);
+        let stores = |i: usize| format!("{:?}", entries.borrow()[i].1);
+        assert_eq!(stores(3), format!("{:?}", [(Dest::Here(-24, Size::Q), 5)]));
+        assert_eq!(stores(2), "[]");
+        interp.borrow_mut().take();
+    }
+}
@@ -1,115 +1,767 @@
-use std::{collections::HashMap};
+use std::{collections::HashMap, cell::RefCell, ops::Range};
 
-use crate::instruction::{Label, Instruction, same_size, Dest, Src, Size};
+use crate::instruction::{Label, Instruction, same_size, Dest, Src, Size, Addr, GlobalId, Signedness};
+use crate::object::{self, Object, layout};
+use crate::trap::{Trap, TrapCode};
+
+mod atomic;
+mod compiled;
+mod error;
+mod ffi;
+mod session;
+mod shadow;
+mod snapshot;
+mod trace;
+
+pub use compiled::{CompiledFn, CompileError};
+pub use error::{InterpError, Problem};
+pub use session::{InterpreterSession, Event, Frame};
+pub use snapshot::{Snapshot, Outcome, SnapshotError};
+pub use trace::TraceEntry;
+use trace::Tracer;
+use ffi::Mock;
+use atomic::{Op, atomic, place_of};
+
+// Src::LabelAddr produces this OR'd with the index of the label in `code`.
+// It's an opaque token, not a real address: the only thing you can do with one is JmpIndirect to it
+const LABEL_ADDR_TAG: u64 = 0x1abe_1000_0000_0000;
+
+// where a run is up to
+struct State {
+    ip: usize,
+    bp: usize,
+    sp: usize,
+    fuel: u64,
+    memory: Vec<u8>,  // [stack | data | globals]
+    defined: Option<Vec<bool>>,  // one per byte of `memory`, in checked mode
+    args: [u64; 6],
+    host_values: bool,  // whether an unmocked FFICall's result has been stored (it could be a host pointer)
+}
 
 pub struct InterpreterFn {  // note: always takes `u64` x 6 and returns u64
     code: Vec<Instruction>,
     stack_size: usize,
 
     label_locations: HashMap<Label, usize>, // index of instruction in `code`
+
+    // the data and globals go right above the stack, so `memory` is [stack | data | globals]
+    data_locations: Vec<usize>, // index in `memory`
+    global_locations: Vec<Range<usize>>,
+    globals_start: usize,
+    // what `memory` starts out as -- the globals get copied back in here after every run
+    memory_template: RefCell<Vec<u8>>,
+
+    // how many instructions a run may execute before it traps with OutOfFuel
+    fuel: u64,
+
+    // in sandbox mode, pointers are offsets into this instead of indexes into `memory`
+    linear_memory: Option<RefCell<Vec<u8>>>,
+
+    // in host mode, addresses are real addresses instead of indexes into `memory`
+    host_memory: bool,
+
+    // in checked mode, reading bytes that were never written is a Problem::Uninitialized
+    checked: bool,
+
+    // FFIFunctions that get replaced by closures, by name
+    mocks: RefCell<HashMap<String, Mock>>,
+
+    tracer: RefCell<Option<Tracer>>,
+    // how many times each instruction has run, if profiling
+    counts: Option<RefCell<Vec<u64>>>,
 }
 
 impl InterpreterFn {
-    pub fn new(code: Vec<Instruction>, stack_size: usize) -> Self {
+    pub fn new(object: Object, stack_size: usize) -> Self {
+        let code = object.instructions;
         let mut label_locations =   HashMap::new();
         for (i, c) in code.iter().enumerate() {
             if let Instruction::Label(l) = c {
                 label_locations.insert(*l, i);
             }
         }
+
+        let (data_locations, globals_start) = layout(stack_size, &object.data);
+        let (global_locations, end) = layout(globals_start, &object.globals);
+        let mut memory_template = vec![0; end];
+        for (blob, location) in object.data.iter().zip(data_locations.iter()).chain(object.globals.iter().zip(global_locations.iter())) {
+            memory_template[*location..*location + blob.bytes.len()].clone_from_slice(&blob.bytes);
+        }
+        let global_locations = object.globals.iter().zip(global_locations)
+            .map(|(blob, location)| location..location + blob.bytes.len())
+            .collect();
+
         InterpreterFn {
-            code, stack_size, label_locations,
+            code, stack_size, label_locations, data_locations, global_locations, globals_start,
+            memory_template: RefCell::new(memory_template),
+            fuel: u64::MAX,
+            linear_memory: None,
+            host_memory: false,
+            checked: false,
+            mocks: RefCell::new(HashMap::new()),
+            tracer: RefCell::new(None),
+            counts: None,
         }
     }
-    pub fn run(&self, arg0: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> u64 {
-        let mut stack = vec![0; self.stack_size];
 
-        let mut ip: usize = 0;
-        let mut bp: usize = self.stack_size; 
-        let mut sp: usize = self.stack_size; 
+    pub fn read_global(&self, global: GlobalId) -> Vec<u8> {
+        object::read_global(&self.global_locations, &self.memory_template.borrow(), global)
+    }
+
+    pub fn write_global(&self, global: GlobalId, bytes: &[u8]) {
+        object::write_global(&self.global_locations, &mut self.memory_template.borrow_mut(), global, bytes)
+    }
 
-        fn load(stack: &Vec<u8>, bp: usize, src: Src) -> u64 {
-            match src {
-                Src::Uninitialized => 0x123456789abcdef0,
-                Src::Imm(i) => i,
-                Src::Ptr(offset_to_ptr, offset_after_ptr, sz) => 
-                    load_relative(stack, load_relative(stack, bp, offset_to_ptr, Size::Q) as usize, offset_after_ptr, sz),
-                Src::Here(stack_offset, sz) => load_relative(stack, bp, stack_offset, sz)
-            }
+    // fuel here is instructions, where the JIT's is labels and entries (see JitFn::set_fuel)
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = fuel;
+    }
+
+    // switches to sandbox mode: every pointer is an offset into `memory`, which is range-checked
+    // (accesses outside it trap with OutOfBounds), and which keeps its contents between runs
+    pub fn set_memory(&mut self, memory: Vec<u8>) {
+        assert!(!self.host_memory, "can't sandbox in host mode");
+        self.linear_memory = Some(RefCell::new(memory));
+    }
+
+    pub fn read_memory(&self) -> Vec<u8> {
+        self.linear_memory.as_ref().expect("not in sandbox mode").borrow().clone()
+    }
+
+    // switches to host mode: the stack, data and globals are still in `memory`, but addresses are real ones,
+    // so pointers that came from FFICall work the same as in the JIT.
+    // unsafe because from then on, running the code can read and write anywhere
+    pub unsafe fn use_host_memory(&mut self) {
+        assert!(self.linear_memory.is_none(), "can't use host memory in a sandbox");
+        assert!(!self.checked, "can't use host memory in checked mode");
+        self.host_memory = true;
+    }
+
+    // turns checked mode on or off: see shadow.rs
+    pub fn set_checked(&mut self, on: bool) {
+        assert!(!(on && self.host_memory), "can't use checked mode with host memory");
+        self.checked = on;
+    }
+
+    // every instruction that runs from now on gets passed to `sink`
+    pub fn set_trace(&mut self, sink: impl FnMut(&TraceEntry) + 'static) {
+        self.tracer = RefCell::new(Some(Tracer { sink: Some(Box::new(sink)), current: vec![] }));
+    }
+
+    pub fn clear_trace(&mut self) {
+        self.tracer = RefCell::new(None);
+    }
+
+    // starts counting how many times each instruction runs (across all runs, until it's turned off)
+    pub fn set_profiling(&mut self, on: bool) {
+        self.counts = if on { Some(RefCell::new(vec![0; self.code.len()])) } else { None };
+    }
+
+    // indexed like the code (None if not profiling)
+    pub fn instruction_counts(&self) -> Option<Vec<u64>> {
+        self.counts.as_ref().map(|counts| counts.borrow().clone())
+    }
+
+    // how many times control went through each label, whether it was jumped to or not
+    pub fn label_counts(&self) -> Option<HashMap<Label, u64>> {
+        let counts = self.instruction_counts()?;
+        Some(self.label_locations.iter().map(|(label, ip)| (*label, counts[*ip])).collect())
+    }
+
+    fn note_load(&self, src: Src, value: u64) {
+        if let Some(entry) = self.tracer.borrow_mut().as_mut().and_then(|tracer| tracer.current.last_mut()) {
+            entry.loads.push((src, value));
         }
+    }
 
-        fn store(stack: &mut Vec<u8>, bp: usize, dest: Dest, value: u64) {
-            match dest {
-                Dest::Nowhere => {}
-                Dest::Ptr(offset_to_ptr, offset_after_ptr, sz) => {
-                    store_relative(stack, load_relative(stack, bp, offset_to_ptr, Size::Q) as usize, offset_after_ptr, sz, value)
-                }
-                Dest::Here(stack_offset, sz) => store_relative(stack, bp, stack_offset, sz, value)
-            }
+    fn note_store(&self, dest: Dest, value: u64) {
+        if let Some(entry) = self.tracer.borrow_mut().as_mut().and_then(|tracer| tracer.current.last_mut()) {
+            entry.stores.push((dest, value));
         }
+    }
+
+    fn invalid(&self, ip: usize, problem: Problem) -> InterpError {
+        InterpError::Invalid { ip, instruction: self.code.get(ip).cloned().map(Box::new), problem }
+    }
 
-        fn load_relative(stack: &Vec<u8>, base: usize, offset: i32, size: Size) -> u64  {
-            let location = (base as i32 + offset) as usize;
-            match size {
-                Size::B => stack[location] as u64,
-                Size::H => u16::from_le_bytes(stack[location..location + 2].try_into().expect("should have room for 2 bytes")) as u64,
-                Size::D => u32::from_le_bytes(stack[location..location + 4].try_into().expect("should have room for 4 bytes")) as u64,
-                Size::Q => u64::from_le_bytes(stack[location..location + 8].try_into().expect("should have room for 8 bytes")) as u64,
+    fn locate(&self, ip: usize, label: Label) -> Result<usize, InterpError> {
+        self.label_locations.get(&label).copied().ok_or_else(|| self.invalid(ip, Problem::UndefinedLabel(label)))
+    }
+
+    fn global_location(&self, ip: usize, global: GlobalId) -> Result<usize, InterpError> {
+        self.global_locations.get(global.0).map(|range| range.start).ok_or_else(|| self.invalid(ip, Problem::UndefinedGlobal(global)))
+    }
+
+    // what index 0 of `memory` is called
+    fn origin(&self, memory: &[u8]) -> usize {
+        if self.host_memory { memory.as_ptr() as usize } else { 0 }
+    }
+
+    pub fn run(&self, arg0: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64, arg5: u64) -> Result<u64, InterpError> {
+        let mut state = self.start([arg0, arg1, arg2, arg3, arg4, arg5]);
+        let result = loop {
+            match self.step(&mut state) {
+                Ok(None) => {}
+                Ok(Some(value)) => break Ok(value),
+                Err(error) => break Err(error),
             }
+        };
+        self.finish(&state);
+        result
+    }
+
+    fn start(&self, args: [u64; 6]) -> State {
+        let memory = self.memory_template.borrow().clone();
+        let bp = self.origin(&memory) + self.stack_size;
+        let defined = if self.checked { Some(self.initial_definedness(&memory)) } else { None };
+        State { ip: 0, bp, sp: bp, fuel: self.fuel, memory, defined, args, host_values: false }
+    }
+
+    // however the run ends, the globals are kept for next time
+    fn finish(&self, state: &State) {
+        self.memory_template.borrow_mut()[self.globals_start..].clone_from_slice(&state.memory[self.globals_start..]);
+    }
+
+    // runs the instruction at state.ip, and returns what it returned if it was an FFIRet
+    fn step(&self, state: &mut State) -> Result<Option<u64>, InterpError> {
+        // out of fuel, the instruction doesn't run at all, so it isn't counted or traced either
+        // (and a paused run picks up from exactly here)
+        if state.fuel == 0 && state.ip < self.code.len() {
+            return Err(Trap { code: TrapCode::OutOfFuel, ir_index: state.ip }.into());
         }
 
-        fn store_relative(stack: &mut Vec<u8>, base: usize, offset: i32, size: Size, value: u64) {
-            let location = (base as i32 + offset) as usize;
-            match size {
-                Size::B => stack[location..location + 1].clone_from_slice(&value.to_le_bytes()[..1]),
-                Size::H => stack[location..location + 2].clone_from_slice(&value.to_le_bytes()[..2]),
-                Size::D => stack[location..location + 4].clone_from_slice(&value.to_le_bytes()[..4]),
-                Size::Q => stack[location..location + 8].clone_from_slice(&value.to_le_bytes()[..8]),
-            }
+        if let Some(counts) = &self.counts {
+            if let Some(count) = counts.borrow_mut().get_mut(state.ip) { *count += 1 }
         }
+        let tracing = match self.tracer.borrow_mut().as_mut() {
+            Some(tracer) if tracer.sink.is_some() => {
+                tracer.current.push(TraceEntry { ip: state.ip, instruction: self.code.get(state.ip).cloned(), loads: vec![], stores: vec![] });
+                true
+            }
+            _ => false,
+        };
+
+        let result = self.execute(state);
 
-        loop {
-            if !(0..self.code.len()).contains(&ip) { 
-                panic!("instruction pointer escaped"); 
+        // the sink gets called with the tracer put away, so it can use the interpreter too
+        if tracing {
+            let finished = self.tracer.borrow_mut().as_mut().and_then(|tracer| Some((tracer.current.pop()?, tracer.sink.take()?)));
+            if let Some((entry, mut sink)) = finished {
+                sink(&entry);
+                if let Some(tracer) = self.tracer.borrow_mut().as_mut() { tracer.sink = Some(sink) }
             }
+        }
+        result
+    }
+
+    fn execute(&self, state: &mut State) -> Result<Option<u64>, InterpError> {
+        let ip = state.ip;
+        let bp = state.bp;
+        let args = state.args;
 
-            match self.code[ip] {
-                Instruction::FFIBegin(n_bytes, args) => {
-                    sp = bp - n_bytes as usize;
-                    store(&mut stack, bp, args[0], arg0);
-                    store(&mut stack, bp, args[1], arg1);
-                    store(&mut stack, bp, args[2], arg2);
-                    store(&mut stack, bp, args[3], arg3);
-                    store(&mut stack, bp, args[4], arg4);
-                    store(&mut stack, bp, args[5], arg5);
+        if !(0..self.code.len()).contains(&ip) { 
+            return Err(self.invalid(ip, Problem::IpEscaped));
+        }
+
+        // (step has already checked there's some left)
+        state.fuel -= 1;
+
+        self.check_definedness(state)?;
+
+        let stack = &mut state.memory;
+
+        match self.code[ip] {
+            Instruction::FFIBegin(n_bytes, dests) => {
+                if n_bytes > self.stack_size as u64 {
+                    return Err(self.invalid(ip, Problem::FrameTooBig(n_bytes)));
                 }
-                Instruction::FFIRet(src) => {
-                    return load(&mut stack, bp, src);
+                state.sp = bp - n_bytes as usize;
+                store(self, stack, bp, ip, dests[0], args[0])?;
+                store(self, stack, bp, ip, dests[1], args[1])?;
+                store(self, stack, bp, ip, dests[2], args[2])?;
+                store(self, stack, bp, ip, dests[3], args[3])?;
+                store(self, stack, bp, ip, dests[4], args[4])?;
+                store(self, stack, bp, ip, dests[5], args[5])?;
+            }
+            Instruction::FFIRet(src) => {
+                return load(self, stack, bp, ip, src).map(Some);
+            }
+            Instruction::Copy(dest, src, count) => {
+                if count.0 != 1 && !same_size(dest, src) {
+                    return Err(self.invalid(ip, Problem::SizeMismatch));
                 }
-                Instruction::Copy(dest, src, count) => {
-                    if count.0 != 1 { assert!(same_size(dest, src)); }
-                    for i in 0..count.0 {
-                        let val = load(&stack, bp, src);
-                        store(&mut stack, bp, dest, val);
-                    }
+                // the same as codegen: each copy is one byte further along than the last
+                for i in 0..count.0 {
+                    let val = load(self, stack, bp, ip, src.offset(i as i32))?;
+                    store(self, stack, bp, ip, dest.offset(i as i32), val)?;
                 }
-                Instruction::JIf(src, label) => {
-                    if load(&stack, bp, src) != 0 {
-                        ip = *self.label_locations.get(&label).expect("label must be defined");
-                        continue;
+            }
+            Instruction::JIf(src, label) => {
+                if load(self, stack, bp, ip, src)? != 0 {
+                    state.ip = self.locate(ip, label)?;
+                    return Ok(None);
+                }
+            }
+            Instruction::Switch(src, default, ref table) => {
+                let index = load(self, stack, bp, ip, src)?;
+                let label = usize::try_from(index).ok().and_then(|i| table.get(i)).unwrap_or(&default);
+                state.ip = self.locate(ip, *label)?;
+                return Ok(None);
+            }
+            Instruction::JmpIndirect(src) => {
+                if self.linear_memory.is_some() { return Err(self.invalid(ip, Problem::NotInSandbox)) }
+                let token = load(self, stack, bp, ip, src)?;
+                let index = (token ^ LABEL_ADDR_TAG) as usize;
+                if token & LABEL_ADDR_TAG != LABEL_ADDR_TAG || !matches!(self.code.get(index), Some(Instruction::Label(_))) {
+                    return Err(self.invalid(ip, Problem::NotALabel(token)));
+                }
+                state.ip = index;
+                return Ok(None);
+            }
+            Instruction::Label(_) => {}
+            Instruction::AtomicLoad(dest, src, ordering) => {
+                if !ordering.valid_for_load() { return Err(self.invalid(ip, Problem::BadOrdering(ordering))) }
+                if !src.needs_load() { return Err(self.invalid(ip, Problem::NotInMemory)) }
+                let val = match place_of(src) {
+                    Some(place) => atomic(self, stack, bp, ip, place, Op::Load, ordering)?,
+                    None => load(self, stack, bp, ip, src)?,
+                };
+                store(self, stack, bp, ip, dest, val)?;
+            }
+            Instruction::AtomicStore(place, src, ordering) => {
+                if !ordering.valid_for_store() { return Err(self.invalid(ip, Problem::BadOrdering(ordering))) }
+                if !place.needs_store() { return Err(self.invalid(ip, Problem::NotInMemory)) }
+                let val = load(self, stack, bp, ip, src)?;
+                atomic(self, stack, bp, ip, place, Op::Store(val), ordering)?;
+            }
+            Instruction::FetchAdd(old, place, src, ordering) => {
+                if !place.needs_store() { return Err(self.invalid(ip, Problem::NotInMemory)) }
+                let val = load(self, stack, bp, ip, src)?;
+                let prev = atomic(self, stack, bp, ip, place, Op::FetchAdd(val), ordering)?;
+                store(self, stack, bp, ip, old, prev)?;
+            }
+            Instruction::Swap(old, place, src, ordering) => {
+                if !place.needs_store() { return Err(self.invalid(ip, Problem::NotInMemory)) }
+                let val = load(self, stack, bp, ip, src)?;
+                let prev = atomic(self, stack, bp, ip, place, Op::Swap(val), ordering)?;
+                store(self, stack, bp, ip, old, prev)?;
+            }
+            Instruction::CompareExchange(old, place, expected, new, ordering) => {
+                if !place.needs_store() { return Err(self.invalid(ip, Problem::NotInMemory)) }
+                let new = load(self, stack, bp, ip, new)?;
+                let expected = load(self, stack, bp, ip, expected)?;
+                let prev = atomic(self, stack, bp, ip, place, Op::CompareExchange(expected, new), ordering)?;
+                store(self, stack, bp, ip, old, prev)?;
+            }
+            Instruction::Fence(ordering) => {
+                if !ordering.valid_for_fence() { return Err(self.invalid(ip, Problem::BadOrdering(ordering))) }
+            }
+            Instruction::AddChecked(dest, a, b, signedness, overflow) |
+            Instruction::SubChecked(dest, a, b, signedness, overflow) |
+            Instruction::MulChecked(dest, a, b, signedness, overflow) => {
+                let a = load(self, stack, bp, ip, a)?;
+                let b = load(self, stack, bp, ip, b)?;
+                let result = match (&self.code[ip], signedness) {
+                    (Instruction::AddChecked(..), Signedness::Unsigned) => a.checked_add(b),
+                    (Instruction::SubChecked(..), Signedness::Unsigned) => a.checked_sub(b),
+                    (Instruction::MulChecked(..), Signedness::Unsigned) => a.checked_mul(b),
+                    (Instruction::AddChecked(..), Signedness::Signed) => (a as i64).checked_add(b as i64).map(|x| x as u64),
+                    (Instruction::SubChecked(..), Signedness::Signed) => (a as i64).checked_sub(b as i64).map(|x| x as u64),
+                    (Instruction::MulChecked(..), Signedness::Signed) => (a as i64).checked_mul(b as i64).map(|x| x as u64),
+                    _ => unreachable!(),
+                };
+                match result {
+                    Some(result) => store(self, stack, bp, ip, dest, result)?,
+                    None => {
+                        state.ip = self.locate(ip, overflow)?;
+                        return Ok(None);
                     }
                 }
-                Instruction::Label(_) => {}
-                Instruction::FFICall(dest, args, func) => {
-                    let result = func(
-                        load(&stack, bp, args[0]), load(&stack, bp, args[1]), load(&stack, bp, args[2]),
-                        load(&stack, bp, args[3]), load(&stack, bp, args[4]), load(&stack, bp, args[5])
-                    );
-                    store(&mut stack, bp, dest, result)
+            }
+            Instruction::Trap(code) => {
+                return Err(Trap { code: TrapCode::User(code), ir_index: ip }.into());
+            }
+            Instruction::FFICall(dest, args, function) => {
+                let result = self.call(ip, function, [
+                    load(self, stack, bp, ip, args[0])?, load(self, stack, bp, ip, args[1])?, load(self, stack, bp, ip, args[2])?,
+                    load(self, stack, bp, ip, args[3])?, load(self, stack, bp, ip, args[4])?, load(self, stack, bp, ip, args[5])?
+                ])?;
+                if dest.needs_store() && !self.mocks.borrow().contains_key(function.name) {
+                    state.host_values = true;
                 }
+                store(self, stack, bp, ip, dest, result)?
             }
+        }
+
+        state.ip += 1;
+        Ok(None)
+    }
+}
 
-            ip += 1;
+fn load(interp: &InterpreterFn, stack: &[u8], bp: usize, ip: usize, src: Src) -> Result<u64, InterpError> {
+    let value = match src {
+        Src::Uninitialized => 0x123456789abcdef0,
+        Src::Imm(i) => i,
+        // an index into `stack` (or a real address), which is what Src::Ptr expects to find
+        Src::AddrOf(stack_offset) => (bp as i64 + stack_offset as i64) as u64,
+        Src::DataAddr(data) => {
+            let location = interp.data_locations.get(data.0).ok_or_else(|| interp.invalid(ip, Problem::UndefinedData(data)))?;
+            (interp.origin(stack) + location) as u64
         }
+        Src::LabelAddr(l) => LABEL_ADDR_TAG | interp.locate(ip, l)? as u64,
+        Src::Ptr(offset_to_ptr, offset_after_ptr, sz) => load_at(interp, stack, bp, ip, Addr::ptr(offset_to_ptr, offset_after_ptr), sz)?,
+        Src::Here(stack_offset, sz) => load_relative(interp, ip, stack, bp, stack_offset, sz)?,
+        Src::At(addr, sz) => load_at(interp, stack, bp, ip, addr, sz)?,
+        Src::Global(global, offset, sz) => 
+            load_relative(interp, ip, stack, interp.origin(stack) + interp.global_location(ip, global)?, offset, sz)?,
+    };
+    if let Src::Ptr(..) | Src::Here(..) | Src::At(..) | Src::Global(..) = src {
+        interp.note_load(src, value);
+    }
+    Ok(value)
+}
+
+fn store(interp: &InterpreterFn, stack: &mut Vec<u8>, bp: usize, ip: usize, dest: Dest, value: u64) -> Result<(), InterpError> {
+    match dest {
+        Dest::Nowhere => {}
+        Dest::Ptr(offset_to_ptr, offset_after_ptr, sz) => store_at(interp, stack, bp, ip, Addr::ptr(offset_to_ptr, offset_after_ptr), sz, value)?,
+        Dest::Here(stack_offset, sz) => {
+            writable(interp, ip, stack, bp.wrapping_add(stack_offset as usize), sz)?;
+            store_relative(interp, ip, stack, bp, stack_offset, sz, value)?
+        }
+        Dest::At(addr, sz) => store_at(interp, stack, bp, ip, addr, sz, value)?,
+        Dest::Global(global, offset, sz) => {
+            let base = interp.origin(stack) + interp.global_location(ip, global)?;
+            writable(interp, ip, stack, base.wrapping_add(offset as usize), sz)?;
+            store_relative(interp, ip, stack, base, offset, sz, value)?
+        }
+    }
+    if dest.needs_store() {
+        interp.note_store(dest, value);
+    }
+    Ok(())
+}
+
+fn load_at(interp: &InterpreterFn, stack: &[u8], bp: usize, ip: usize, addr: Addr, sz: Size) -> Result<u64, InterpError> {
+    match &interp.linear_memory {
+        Some(memory) if !addr.chain.offsets().is_empty() => {
+            let location = linear_address(interp, &memory.borrow(), stack, bp, ip, addr, sz)?;
+            load_relative(interp, ip, &memory.borrow(), location, 0, sz)
+        }
+        _ => load_relative(interp, ip, stack, base_of(interp, stack, bp, ip, addr)?, addr.disp, sz),
+    }
+}
+
+fn store_at(interp: &InterpreterFn, stack: &mut Vec<u8>, bp: usize, ip: usize, addr: Addr, sz: Size, value: u64) -> Result<(), InterpError> {
+    match &interp.linear_memory {
+        Some(memory) if !addr.chain.offsets().is_empty() => {
+            let location = linear_address(interp, &memory.borrow(), stack, bp, ip, addr, sz)?;
+            store_relative(interp, ip, &mut memory.borrow_mut(), location, 0, sz, value)
+        }
+        _ => {
+            let base = base_of(interp, stack, bp, ip, addr)?;
+            writable(interp, ip, stack, base.wrapping_add(addr.disp as usize), sz)?;
+            store_relative(interp, ip, stack, base, addr.disp, sz, value)
+        }
+    }
+}
+
+// the data is read-only, like it is in the JIT
+fn writable(interp: &InterpreterFn, ip: usize, stack: &[u8], location: usize, sz: Size) -> Result<(), InterpError> {
+    let index = location.wrapping_sub(interp.origin(stack));
+    if index < interp.globals_start && index.wrapping_add(sz.n_bytes() as usize) > interp.stack_size {
+        return Err(interp.invalid(ip, Problem::ReadOnly(location)));
+    }
+    Ok(())
+}
+
+// the same as base_of + disp, except that only the first pointer comes from the stack:
+// the rest of the chain, and the place itself, have to be in the linear memory
+fn linear_address(interp: &InterpreterFn, memory: &[u8], stack: &[u8], bp: usize, ip: usize, addr: Addr, sz: Size) -> Result<usize, InterpError> {
+    let in_bounds = |address: u64, sz: Size| match address.checked_add(sz.n_bytes()) {
+        Some(end) if end <= memory.len() as u64 => Ok(address as usize),
+        _ => Err(InterpError::Trap(Trap { code: TrapCode::OutOfBounds, ir_index: ip })),
+    };
+
+    let (first, rest) = addr.chain.offsets().split_first().expect("the stack isn't in the linear memory");
+    let mut address = load_relative(interp, ip, stack, bp, *first, Size::Q)?;
+    for offset in rest {
+        let location = in_bounds(address.wrapping_add(*offset as u64), Size::Q)?;
+        address = load_relative(interp, ip, memory, location, 0, Size::Q)?;
+    }
+    if let Some((index_offset, scale)) = addr.index {
+        let index = load_relative(interp, ip, stack, bp, index_offset, Size::Q)?;
+        address = address.wrapping_add(index.wrapping_mul(scale.n_bytes()));
+    }
+    in_bounds(address.wrapping_add(addr.disp as u64), sz)
+}
+
+// everything but the displacement
+fn base_of(interp: &InterpreterFn, stack: &[u8], bp: usize, ip: usize, addr: Addr) -> Result<usize, InterpError> {
+    let mut base = bp;
+    for offset in addr.chain.offsets() {
+        base = load_relative(interp, ip, stack, base, *offset, Size::Q)? as usize;
+    }
+    if let Some((index_offset, scale)) = addr.index {
+        let index = load_relative(interp, ip, stack, bp, index_offset, Size::Q)?;
+        base = base.wrapping_add(index.wrapping_mul(scale.n_bytes()) as usize);
     }
-}
\ No newline at end of file
+    Ok(base)
+}
+
+// in host mode, `base` is a real address and `stack` is ignored
+fn load_relative(interp: &InterpreterFn, ip: usize, stack: &[u8], base: usize, offset: i32, size: Size) -> Result<u64, InterpError>  {
+    let location = base.wrapping_add(offset as usize);
+    let n_bytes = size.n_bytes() as usize;
+    let mut bytes = [0; 8];
+    if interp.host_memory {
+        unsafe { std::ptr::copy_nonoverlapping(location as *const u8, bytes.as_mut_ptr(), n_bytes) }
+    } else {
+        let range = in_range(interp, ip, stack, location, n_bytes)?;
+        bytes[..n_bytes].clone_from_slice(&stack[range]);
+    }
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn store_relative(interp: &InterpreterFn, ip: usize, stack: &mut [u8], base: usize, offset: i32, size: Size, value: u64) -> Result<(), InterpError> {
+    let location = base.wrapping_add(offset as usize);
+    let n_bytes = size.n_bytes() as usize;
+    if interp.host_memory {
+        unsafe { std::ptr::copy_nonoverlapping(value.to_le_bytes().as_ptr(), location as *mut u8, n_bytes) }
+    } else {
+        let range = in_range(interp, ip, stack, location, n_bytes)?;
+        stack[range].clone_from_slice(&value.to_le_bytes()[..n_bytes]);
+    }
+    Ok(())
+}
+
+fn in_range(interp: &InterpreterFn, ip: usize, stack: &[u8], location: usize, n_bytes: usize) -> Result<Range<usize>, InterpError> {
+    match location.checked_add(n_bytes) {
+        Some(end) if end <= stack.len() => Ok(location..end),
+        _ => Err(interp.invalid(ip, Problem::OutOfRange(location))),
+    }
+}
+
+// for the tests here and in the modules below
+#[cfg(test)]
+pub(super) mod test_util {
+    use super::*;
+
+    pub const N: Dest = Dest::Nowhere;
+
+    // with a 1024 byte stack, Here(offset) is memory[1024 + offset]
+    pub fn interpreter(instructions: Vec<Instruction>) -> InterpreterFn {
+        InterpreterFn::new(Object { instructions, data: vec![], globals: vec![] }, 1024)
+    }
+
+    pub fn problem(result: Result<u64, InterpError>) -> Problem {
+        match result {
+            Err(error) => invalid(error).1,
+            result => panic!("expected a Problem, not {:?}", result),
+        }
+    }
+
+    // where the problem was, and what it was
+    pub fn invalid(error: InterpError) -> (usize, Problem) {
+        match error {
+            InterpError::Invalid { ip, problem, .. } => (ip, problem),
+            error => panic!("expected a Problem, not {:?}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_util::*;
+    use crate::instruction::Count;
+
+    #[test]
+    fn no_jmp_indirect_in_a_sandbox() {
+        let code = || vec![
+            Instruction::FFIBegin(16, [N; 6]),
+            Instruction::Copy(Dest::Here(-8, Size::Q), Src::LabelAddr(Label(0)), Count(1)),
+            Instruction::JmpIndirect(Src::Here(-8, Size::Q)),
+            Instruction::Label(Label(0)),
+            Instruction::FFIRet(Src::Imm(1)),
+        ];
+        assert_eq!(interpreter(code()).run(0, 0, 0, 0, 0, 0).unwrap(), 1);
+
+        let mut sandboxed = interpreter(code());
+        sandboxed.set_memory(vec![0; 64]);
+        assert_eq!(problem(sandboxed.run(0, 0, 0, 0, 0, 0)), Problem::NotInSandbox);
+    }
+
+    #[test]
+    fn data_is_read_only() {
+        let object = || Object {
+            instructions: vec![
+                Instruction::FFIBegin(8, [N; 6]),
+                Instruction::Copy(Dest::Here(-8, Size::Q), Src::DataAddr(crate::instruction::DataId(0)), Count(1)),
+                Instruction::Copy(Dest::Ptr(-8, 2, Size::B), Src::Imm(0), Count(1)),
+                Instruction::FFIRet(Src::Imm(0)),
+            ],
+            data: vec![crate::object::Blob { name: "d".to_string(), align: 8, bytes: vec![1; 4] }],
+            globals: vec![],
+        };
+        let reference = InterpreterFn::new(object(), 1024).run(0, 0, 0, 0, 0, 0);
+        let compiled = InterpreterFn::new(object(), 1024).compile().unwrap().run(0, 0, 0, 0, 0, 0);
+        assert_eq!(problem(reference), Problem::ReadOnly(1026));
+        assert_eq!(problem(compiled), Problem::ReadOnly(1026));
+    }
+
+    #[test]
+    fn switch_takes_the_default_when_out_of_range() {
+        // table entry i returns i + 1, and the default returns 100
+        let code = |n_labels: u64| {
+            let mut code = vec![
+                Instruction::FFIBegin(8, [Dest::Here(-8, Size::Q), N, N, N, N, N]),
+                Instruction::Switch(Src::Here(-8, Size::Q), Label(100), (0..n_labels).map(Label).collect()),
+                Instruction::Label(Label(100)),
+                Instruction::FFIRet(Src::Imm(100)),
+            ];
+            for i in 0..n_labels {
+                code.extend([Instruction::Label(Label(i)), Instruction::FFIRet(Src::Imm(i + 1))]);
+            }
+            code
+        };
+
+        let interp = interpreter(code(3));
+        let compiled = interp.compile().unwrap();
+        for (index, expected) in [(0, 1), (1, 2), (2, 3), (3, 100), (4, 100), (1 << 32, 100), (-1i64 as u64, 100), (u64::MAX / 2 + 1, 100)] {
+            assert_eq!(interp.run(index, 0, 0, 0, 0, 0).unwrap(), expected, "index {:#x}", index);
+            assert_eq!(compiled.run(index, 0, 0, 0, 0, 0).unwrap(), expected, "compiled, index {:#x}", index);
+        }
+
+        // with no table, everything's out of range
+        let empty = interpreter(code(0));
+        assert_eq!(empty.run(0, 0, 0, 0, 0, 0).unwrap(), 100);
+        assert_eq!(empty.compile().unwrap().run(0, 0, 0, 0, 0, 0).unwrap(), 100);
+    }
+
+    #[test]
+    fn checked_arithmetic_at_the_boundaries() {
+        use crate::instruction::Signedness::{Signed, Unsigned};
+        let (min, max) = (i64::MIN as u64, i64::MAX as u64);
+        let neg = |x: i64| x as u64;
+
+        // None means it overflowed
+        let add = |sign, a, b, sum| (Instruction::AddChecked as fn(_, _, _, _, _) -> _, sign, a, b, sum);
+        let sub = |sign, a, b, difference| (Instruction::SubChecked as fn(_, _, _, _, _) -> _, sign, a, b, difference);
+        let mul = |sign, a, b, product| (Instruction::MulChecked as fn(_, _, _, _, _) -> _, sign, a, b, product);
+        let cases = [
+            add(Unsigned, u64::MAX - 1, 1, Some(u64::MAX)),
+            add(Unsigned, u64::MAX, 1, None),
+            add(Unsigned, max, 1, Some(min)),
+            add(Signed, max - 1, 1, Some(max)),
+            add(Signed, max, 1, None),
+            add(Signed, min, neg(-1), None),
+            add(Signed, u64::MAX, 1, Some(0)),
+            sub(Unsigned, 1, 1, Some(0)),
+            sub(Unsigned, 0, 1, None),
+            sub(Signed, 0, 1, Some(neg(-1))),
+            sub(Signed, min + 1, 1, Some(min)),
+            sub(Signed, min, 1, None),
+            sub(Signed, max, neg(-1), None),
+            sub(Signed, 0, min, None),
+            mul(Unsigned, 1 << 32, (1 << 32) - 1, Some(u64::MAX - ((1 << 32) - 1))),
+            mul(Unsigned, 1 << 32, 1 << 32, None),
+            mul(Unsigned, u64::MAX, 1, Some(u64::MAX)),
+            mul(Unsigned, u64::MAX, 2, None),
+            mul(Signed, neg(-1), neg(-1), Some(1)),
+            mul(Signed, min, 1, Some(min)),
+            mul(Signed, min, neg(-1), None),
+            mul(Signed, 1 << 62, 2, None),
+            mul(Signed, 1 << 62, neg(-2), Some(min)),
+            mul(Signed, 3_037_000_499, 3_037_000_499, Some(9_223_372_030_926_249_001)),
+            mul(Signed, 3_037_000_500, 3_037_000_500, None),
+        ];
+
+        for (op, sign, a, b, expected) in cases {
+            let interp = interpreter(vec![
+                Instruction::FFIBegin(24, [Dest::Here(-8, Size::Q), Dest::Here(-16, Size::Q), N, N, N, N]),
+                Instruction::Copy(Dest::Here(-24, Size::Q), Src::Imm(0x5a5a), Count(1)),
+                op(Dest::Here(-24, Size::Q), Src::Here(-8, Size::Q), Src::Here(-16, Size::Q), sign, Label(1)),
+                Instruction::FFIRet(Src::Here(-24, Size::Q)),
+                Instruction::Label(Label(1)),
+                Instruction::FFIRet(Src::Here(-24, Size::Q)),
+            ]);
+            // an overflow jumps to the label, leaving the dest alone
+            let expected = expected.unwrap_or(0x5a5a);
+            assert_eq!(interp.run(a, b, 0, 0, 0, 0).unwrap(), expected, "{:?} {:#x} {:#x}", sign, a, b);
+            assert_eq!(interp.compile().unwrap().run(a, b, 0, 0, 0, 0).unwrap(), expected, "compiled, {:?} {:#x} {:#x}", sign, a, b);
+        }
+    }
+
+    // (ReadOnly, NotInSandbox and the mock, atomic and checked mode ones have tests of their own)
+    #[test]
+    fn every_problem() {
+        use crate::instruction::{DataId, GlobalId, Ordering};
+        let q = Dest::Here(-8, Size::Q);
+        let cases = [
+            (Instruction::FFIBegin(2048, [N; 6]), Problem::FrameTooBig(2048)),
+            (Instruction::JIf(Src::Imm(1), Label(9)), Problem::UndefinedLabel(Label(9))),
+            (Instruction::Copy(q, Src::DataAddr(DataId(0)), Count(1)), Problem::UndefinedData(DataId(0))),
+            (Instruction::Copy(Dest::Global(GlobalId(0), 0, Size::Q), Src::Imm(0), Count(1)), Problem::UndefinedGlobal(GlobalId(0))),
+            (Instruction::Copy(Dest::Here(4, Size::Q), Src::Imm(0), Count(1)), Problem::OutOfRange(1028)),
+            (Instruction::Copy(q, Src::Here(-16, Size::D), Count(2)), Problem::SizeMismatch),
+            (Instruction::JmpIndirect(Src::Imm(5)), Problem::NotALabel(5)),
+            (Instruction::Fence(Ordering::Relaxed), Problem::BadOrdering(Ordering::Relaxed)),
+            (Instruction::AtomicLoad(q, Src::Here(-8, Size::Q), Ordering::Release), Problem::BadOrdering(Ordering::Release)),
+            (Instruction::FetchAdd(q, N, Src::Imm(1), Ordering::SeqCst), Problem::NotInMemory),
+        ];
+        for (instruction, expected) in cases {
+            // the problem is always with the second instruction, unless it's the first
+            let ip = if let Instruction::FFIBegin(..) = instruction { 0 } else { 1 };
+            let code = match ip {
+                0 => vec![instruction.clone(), Instruction::FFIRet(Src::Imm(0))],
+                _ => vec![Instruction::FFIBegin(16, [N; 6]), instruction.clone(), Instruction::FFIRet(Src::Imm(0))],
+            };
+            let interp = interpreter(code);
+            for result in [interp.run(0, 0, 0, 0, 0, 0), interp.compile().unwrap().run(0, 0, 0, 0, 0, 0)] {
+                match result {
+                    Err(InterpError::Invalid { ip: at, instruction: Some(at_instruction), problem }) => {
+                        assert_eq!((at, problem), (ip, expected));
+                        assert_eq!(format!("{:?}", at_instruction), format!("{:?}", instruction));
+                    }
+                    result => panic!("{:?}: {:?}", instruction, result),
+                }
+            }
+        }
+
+        // running off the end isn't any instruction's fault
+        let interp = interpreter(vec![Instruction::FFIBegin(16, [N; 6])]);
+        for result in [interp.run(0, 0, 0, 0, 0, 0), interp.compile().unwrap().run(0, 0, 0, 0, 0, 0)] {
+            assert!(matches!(result, Err(InterpError::Invalid { ip: 1, instruction: None, problem: Problem::IpEscaped })), "{:?}", result);
+        }
+    }
+
+    #[test]
+    fn label_counts() {
+        // counts arg0 down to 0
+        let code = vec![
+            Instruction::FFIBegin(8, [Dest::Here(-8, Size::Q), N, N, N, N, N]),
+            Instruction::Label(Label(0)),
+            Instruction::JIf(Src::Here(-8, Size::Q), Label(1)),
+            Instruction::FFIRet(Src::Imm(0)),
+            Instruction::Label(Label(1)),
+            Instruction::SubChecked(Dest::Here(-8, Size::Q), Src::Here(-8, Size::Q), Src::Imm(1), Signedness::Unsigned, Label(2)),
+            Instruction::JIf(Src::Imm(1), Label(0)),
+            Instruction::Label(Label(2)),
+            Instruction::Trap(1),
+        ];
+        let mut interp = interpreter(code);
+        assert!(interp.label_counts().is_none());
+        assert!(interp.instruction_counts().is_none());
+
+        interp.set_profiling(true);
+        interp.run(3, 0, 0, 0, 0, 0).unwrap();
+        interp.run(1, 0, 0, 0, 0, 0).unwrap();
+        let counts = interp.label_counts().unwrap();
+        assert_eq!((counts[&Label(0)], counts[&Label(1)], counts[&Label(2)]), (6, 4, 0));
+        assert_eq!(interp.instruction_counts().unwrap(), [2, 6, 6, 2, 4, 4, 4, 0, 0]);
+
+        interp.set_profiling(false);
+        assert!(interp.label_counts().is_none());
+    }
+}
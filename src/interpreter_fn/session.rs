@@ -0,0 +1,290 @@
+use std::{collections::HashSet, fmt};
+
+use crate::instruction::{Label, Instruction, Size, Src};
+use super::{InterpreterFn, InterpError, State, load};
+
+// runs an InterpreterFn an instruction at a time, for debugging.
+// the globals are only kept for next time if the run gets to the end
+pub struct InterpreterSession<'a> {
+    interp: &'a InterpreterFn,
+    state: State,
+    breakpoints: HashSet<usize>,  // indexes into `code`
+    watchpoints: Vec<Watchpoint>,
+    finished: bool,
+}
+
+// a Here slot, and what was in it last time it was looked at (None if it couldn't be read)
+struct Watchpoint {
+    offset: i32,
+    size: Size,
+    value: Option<u64>,
+}
+
+// why a session stopped
+#[derive(Clone, Debug)]
+pub enum Event {
+    Stepped,
+    Breakpoint(usize),  // about to run the instruction there
+    Watchpoint { offset: i32, old: Option<u64>, new: Option<u64> },
+    Returned(u64),
+    Failed(InterpError),
+}
+
+// a copy of the current frame
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub ip: usize,
+    pub instruction: Option<Instruction>,
+    pub bp: usize,
+    pub sp: usize,
+    pub bytes: Vec<u8>,  // from sp up to bp
+}
+
+impl<'a> InterpreterSession<'a> {
+    pub fn new(interp: &'a InterpreterFn, args: [u64; 6]) -> Self {
+        InterpreterSession { interp, state: interp.start(args), breakpoints: HashSet::new(), watchpoints: vec![], finished: false }
+    }
+
+    pub fn ip(&self) -> usize {
+        self.state.ip
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    pub fn break_at(&mut self, ip: usize) {
+        self.breakpoints.insert(ip);
+    }
+
+    // false if there's no such label
+    pub fn break_at_label(&mut self, label: Label) -> bool {
+        match self.interp.label_locations.get(&label) {
+            Some(&ip) => { self.break_at(ip); true }
+            None => false,
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, ip: usize) {
+        self.breakpoints.remove(&ip);
+    }
+
+    // stops whenever Src::Here(offset, size) changes
+    pub fn watch(&mut self, offset: i32, size: Size) {
+        let value = self.here(offset, size);
+        self.watchpoints.push(Watchpoint { offset, size, value });
+    }
+
+    pub fn step(&mut self) -> Event {
+        assert!(!self.finished, "the run is already over");
+
+        let event = match self.interp.step(&mut self.state) {
+            Ok(None) => self.check_watchpoints().unwrap_or(Event::Stepped),
+            Ok(Some(value)) => Event::Returned(value),
+            Err(error) => Event::Failed(error),
+        };
+        if let Event::Returned(_) | Event::Failed(_) = event {
+            self.finished = true;
+            self.interp.finish(&self.state);
+        }
+        event
+    }
+
+    // steps n times, or fewer if something happens first (like getting to a breakpoint)
+    pub fn step_by(&mut self, n: usize) -> Event {
+        for _ in 0..n {
+            match self.step() {
+                Event::Stepped => {}
+                event => return event,
+            }
+            if self.breakpoints.contains(&self.state.ip) {
+                return Event::Breakpoint(self.state.ip)
+            }
+        }
+        Event::Stepped
+    }
+
+    // steps at least once, then until something happens
+    pub fn run(&mut self) -> Event {
+        self.step_by(usize::MAX)
+    }
+
+    pub fn frame(&self) -> Frame {
+        let (bp, sp) = (self.state.bp, self.state.sp);
+        let bytes = (sp as isize - bp as isize..0)
+            .map(|offset| self.here(offset as i32, Size::B).unwrap_or(0) as u8)
+            .collect();
+        Frame { ip: self.state.ip, instruction: self.interp.code.get(self.state.ip).cloned(), bp, sp, bytes }
+    }
+
+    fn here(&self, offset: i32, size: Size) -> Option<u64> {
+        load(self.interp, &self.state.memory, self.state.bp, self.state.ip, Src::Here(offset, size)).ok()
+    }
+
+    // updates all of them, but only reports the first one that changed
+    fn check_watchpoints(&mut self) -> Option<Event> {
+        let mut event = None;
+        for i in 0..self.watchpoints.len() {
+            let Watchpoint { offset, size, value: old } = self.watchpoints[i];
+            let new = self.here(offset, size);
+            if new != old && event.is_none() {
+                event = Some(Event::Watchpoint { offset, old, new });
+            }
+            self.watchpoints[i].value = new;
+        }
+        event
+    }
+}
+
+impl Frame {
+    // what Src::Here(offset, size) would read, if it's in the frame
+    pub fn here(&self, offset: i32, size: Size) -> Option<u64> {
+        let start = (offset as isize + self.bytes.len() as isize).try_into().ok()?;
+        let bytes = self.bytes.get(start..start + size.n_bytes() as usize)?;
+        let mut value = [0; 8];
+        value[..bytes.len()].clone_from_slice(bytes);
+        Some(u64::from_le_bytes(value))
+    }
+}
+
+// one line per 8 bytes, lowest address first:
+// bp-16  88 77 66 55 44 33 22 11  Q 0x1122334455667788  D 0x55667788 0x11223344
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.instruction {
+            Some(instruction) => writeln!(f, "ip {}: {:?}", self.ip, instruction)?,
+            None => writeln!(f, "ip {}: (outside the code)", self.ip)?,
+        }
+        writeln!(f, "bp {:#x}  sp {:#x}", self.bp, self.sp)?;
+
+        for (i, row) in self.bytes.chunks(8).enumerate() {
+            let offset = (i * 8) as isize - self.bytes.len() as isize;
+            write!(f, "bp{:<5}", offset)?;
+            for byte in row {
+                write!(f, " {:02x}", byte)?;
+            }
+            if row.len() == 8 {
+                let q = self.here(offset as i32, Size::Q).expect("the row is in the frame");
+                write!(f, "  Q {:#018x}  D {:#010x} {:#010x}", q, q as u32, q >> 32)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::{Dest, Count, Signedness::Unsigned};
+    use crate::interpreter_fn::test_util::{N, interpreter};
+    use crate::trap::{Trap, TrapCode};
+
+    // adds up arg0, arg0 - 1, .. 1, and traps if arg0 is 0
+    fn sum() -> InterpreterFn {
+        interpreter(vec![
+            Instruction::FFIBegin(16, [Dest::Here(-8, Size::Q), N, N, N, N, N]),
+            Instruction::Copy(Dest::Here(-16, Size::Q), Src::Imm(0), Count(1)),
+            Instruction::Label(Label(0)),
+            Instruction::AddChecked(Dest::Here(-16, Size::Q), Src::Here(-16, Size::Q), Src::Here(-8, Size::Q), Unsigned, Label(1)),
+            Instruction::SubChecked(Dest::Here(-8, Size::Q), Src::Here(-8, Size::Q), Src::Imm(1), Unsigned, Label(1)),
+            Instruction::JIf(Src::Here(-8, Size::Q), Label(0)),
+            Instruction::FFIRet(Src::Here(-16, Size::Q)),
+            Instruction::Label(Label(1)),
+            Instruction::Trap(3),
+        ])
+    }
+
+    #[test]
+    fn steps_one_at_a_time() {
+        let interp = sum();
+        let mut session = InterpreterSession::new(&interp, [2, 0, 0, 0, 0, 0]);
+        for ip in [1, 2, 3, 4, 5, 2, 3, 4, 5, 6] {
+            assert!(matches!(session.step(), Event::Stepped));
+            assert_eq!(session.ip(), ip);
+        }
+        assert!(!session.is_finished());
+        assert!(matches!(session.step(), Event::Returned(3)));
+        assert!(session.is_finished());
+    }
+
+    #[test]
+    fn step_by_stops_early() {
+        let interp = sum();
+        let mut session = InterpreterSession::new(&interp, [2, 0, 0, 0, 0, 0]);
+        assert!(matches!(session.step_by(3), Event::Stepped));
+        assert_eq!(session.ip(), 3);
+
+        session.break_at(2);
+        assert!(matches!(session.step_by(100), Event::Breakpoint(2)));
+        assert!(matches!(session.step_by(100), Event::Returned(3)));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let interp = sum();
+        let mut session = InterpreterSession::new(&interp, [3, 0, 0, 0, 0, 0]);
+        session.break_at(5);
+        for _ in 0..3 {
+            assert!(matches!(session.run(), Event::Breakpoint(5)));
+            assert_eq!(session.ip(), 5);
+        }
+        assert!(matches!(session.run(), Event::Returned(6)));
+
+        // by label, and taken out again
+        let mut session = InterpreterSession::new(&interp, [3, 0, 0, 0, 0, 0]);
+        assert!(session.break_at_label(Label(0)));
+        assert!(!session.break_at_label(Label(7)));
+        assert!(matches!(session.run(), Event::Breakpoint(2)));
+        assert!(matches!(session.run(), Event::Breakpoint(2)));
+        session.remove_breakpoint(2);
+        assert!(matches!(session.run(), Event::Returned(6)));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let interp = sum();
+        let mut session = InterpreterSession::new(&interp, [3, 0, 0, 0, 0, 0]);
+        session.watch(-16, Size::Q);
+        // setting it to the 0 it already was isn't a change
+        match session.run() {
+            Event::Watchpoint { offset: -16, old: Some(0), new: Some(3) } => assert_eq!(session.ip(), 4),
+            event => panic!("{:?}", event),
+        }
+        assert!(matches!(session.run(), Event::Watchpoint { offset: -16, old: Some(3), new: Some(5) }));
+        assert!(matches!(session.run(), Event::Watchpoint { offset: -16, old: Some(5), new: Some(6) }));
+        assert!(matches!(session.run(), Event::Returned(6)));
+    }
+
+    #[test]
+    fn failing() {
+        let interp = sum();
+        let mut session = InterpreterSession::new(&interp, [0, 0, 0, 0, 0, 0]);
+        match session.run() {
+            Event::Failed(InterpError::Trap(trap)) => assert_eq!(trap, Trap { code: TrapCode::User(3), ir_index: 8 }),
+            event => panic!("{:?}", event),
+        }
+        assert!(session.is_finished());
+    }
+
+    #[test]
+    fn frames() {
+        let interp = sum();
+        let mut session = InterpreterSession::new(&interp, [0x1122_3344_5566_7788, 0, 0, 0, 0, 0]);
+        session.step_by(2);
+        let frame = session.frame();
+        assert_eq!(frame.ip, 2);
+        assert!(matches!(frame.instruction, Some(Instruction::Label(Label(0)))));
+        assert_eq!((frame.bp - frame.sp, frame.bytes.len()), (16, 16));
+        assert_eq!(frame.bytes[8..], 0x1122_3344_5566_7788u64.to_le_bytes());
+
+        assert_eq!(frame.here(-8, Size::Q), Some(0x1122_3344_5566_7788));
+        assert_eq!(frame.here(-4, Size::D), Some(0x1122_3344));
+        assert_eq!(frame.here(-7, Size::B), Some(0x77));
+        assert_eq!(frame.here(-16, Size::Q), Some(0));
+        // not all in the frame
+        assert_eq!(frame.here(-4, Size::Q), None);
+        assert_eq!(frame.here(-24, Size::Q), None);
+        assert!(frame.to_string().contains("bp-8    88 77 66 55 44 33 22 11  Q 0x1122334455667788  D 0x55667788 0x11223344"));
+    }
+}
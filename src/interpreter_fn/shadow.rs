@@ -0,0 +1,238 @@
+use std::ops::Range;
+
+use crate::instruction::{Instruction, Src, Dest, Size, Addr};
+use super::{InterpreterFn, InterpError, Problem, State, load_relative};
+
+// checked mode keeps a bool per byte of `memory`, saying whether anything has been written there.
+// the data and globals start out defined and the stack doesn't.
+// copying undefined bytes just copies their undefinedness (so does Src::Uninitialized), but anything else
+// that reads them -- a condition, an operand, a pointer that gets followed, a return value -- is a Problem::Uninitialized.
+// the sandbox's memory isn't tracked
+
+impl InterpreterFn {
+    pub(super) fn initial_definedness(&self, memory: &[u8]) -> Vec<bool> {
+        (0..memory.len()).map(|i| i >= self.stack_size).collect()
+    }
+
+    // checks what the instruction at state.ip is going to read, and marks what it's going to write
+    pub(super) fn check_definedness(&self, state: &mut State) -> Result<(), InterpError> {
+        let (memory, bp, ip) = (&state.memory, state.bp, state.ip);
+        let defined = match &mut state.defined {
+            Some(defined) => defined,
+            None => return Ok(()),
+        };
+
+        // what gets read, and what gets written along with where its bytes come from (None: they're all defined)
+        let mut uses: Vec<Src> = vec![];
+        let mut writes: Vec<(Dest, Option<Src>)> = vec![];
+        match self.code[ip] {
+            Instruction::FFIBegin(_, dests) => writes.extend(dests.iter().map(|dest| (*dest, None))),
+            Instruction::FFIRet(src) | Instruction::JIf(src, _) | Instruction::Switch(src, ..) | Instruction::JmpIndirect(src) => uses.push(src),
+            Instruction::Copy(dest, src, count) =>
+                writes.extend((0..count.0).map(|i| (dest.offset(i as i32), Some(src.offset(i as i32))))),
+            Instruction::AtomicLoad(dest, src, _) | Instruction::AtomicStore(dest, src, _) => writes.push((dest, Some(src))),
+            Instruction::FetchAdd(old, place, src, _) => {
+                uses.extend([place.as_src(), src]);
+                writes.extend([(place, None), (old, None)]);
+            }
+            Instruction::Swap(old, place, src, _) => writes.extend([(place, Some(src)), (old, Some(place.as_src()))]),
+            Instruction::CompareExchange(old, place, expected, new, _) => {
+                uses.extend([place.as_src(), expected, new]);
+                writes.extend([(place, None), (old, None)]);
+            }
+            Instruction::AddChecked(dest, a, b, ..) | Instruction::SubChecked(dest, a, b, ..) | Instruction::MulChecked(dest, a, b, ..) => {
+                uses.extend([a, b]);
+                writes.push((dest, None));
+            }
+            Instruction::FFICall(dest, args, _) => {
+                uses.extend(args);
+                writes.push((dest, None));
+            }
+            Instruction::Label(_) | Instruction::Fence(_) | Instruction::Trap(_) => {}
+        }
+
+        for src in uses {
+            if let Some(range) = self.place_in_memory(memory, defined, bp, ip, src)? {
+                self.check_defined(defined, ip, range)?;
+            }
+        }
+
+        // a Swap reads the place it writes, so everything's worked out before anything's marked.
+        // but each copy of a Copy happens after the last (and an overlapping one can read what it just wrote)
+        let one_at_a_time = matches!(self.code[ip], Instruction::Copy(..));
+        let mut marks = vec![];
+        for (dest, from) in writes {
+            if let Some(range) = self.place_in_memory(memory, defined, bp, ip, dest.as_src())? {
+                let bytes = match from {
+                    Some(Src::Uninitialized) => vec![false; 8],
+                    Some(src) => match self.place_in_memory(memory, defined, bp, ip, src)? {
+                        // zero-extended, so anything past the end of the source is defined
+                        Some(from) => (0..8).map(|i| i >= from.len() || defined.get(from.start + i).copied().unwrap_or(true)).collect(),
+                        None => vec![true; 8],
+                    },
+                    None => vec![true; 8],
+                };
+                marks.push((range, bytes));
+                if one_at_a_time {
+                    mark(defined, marks.drain(..));
+                }
+            }
+        }
+        mark(defined, marks.drain(..));
+        Ok(())
+    }
+
+    // the bytes of `memory` that `src` reads, or None if it doesn't read `memory`.
+    // any pointer followed to get there has to be defined
+    fn place_in_memory(&self, memory: &[u8], defined: &[bool], bp: usize, ip: usize, src: Src) -> Result<Option<Range<usize>>, InterpError> {
+        let (location, sz) = match src {
+            Src::Here(stack_offset, sz) => (bp.wrapping_add(stack_offset as usize), sz),
+            Src::Global(global, offset, sz) => (self.global_location(ip, global)?.wrapping_add(offset as usize), sz),
+            Src::Ptr(offset_to_ptr, offset_after_ptr, sz) => match self.address_in_memory(memory, defined, bp, ip, Addr::ptr(offset_to_ptr, offset_after_ptr))? {
+                Some(location) => (location, sz),
+                None => return Ok(None),
+            },
+            Src::At(addr, sz) => match self.address_in_memory(memory, defined, bp, ip, addr)? {
+                Some(location) => (location, sz),
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+        Ok(Some(location..location.wrapping_add(sz.n_bytes() as usize)))
+    }
+
+    // base_of + disp, or None if it's in the sandbox
+    fn address_in_memory(&self, memory: &[u8], defined: &[bool], bp: usize, ip: usize, addr: Addr) -> Result<Option<usize>, InterpError> {
+        if let Some((index_offset, _)) = addr.index {
+            self.check_defined(defined, ip, slot(bp, index_offset))?;
+        }
+
+        let sandboxed = self.linear_memory.is_some() && !addr.chain.offsets().is_empty();
+        let mut base = bp;
+        for offset in addr.chain.offsets() {
+            self.check_defined(defined, ip, slot(base, *offset))?;
+            if sandboxed { return Ok(None) }  // only the first pointer is on the stack
+            base = load_relative(self, ip, memory, base, *offset, Size::Q)? as usize;
+        }
+        if let Some((index_offset, scale)) = addr.index {
+            let index = load_relative(self, ip, memory, bp, index_offset, Size::Q)?;
+            base = base.wrapping_add(index.wrapping_mul(scale.n_bytes()) as usize);
+        }
+        Ok(Some(base.wrapping_add(addr.disp as usize)))
+    }
+
+    // bytes outside `memory` aren't this check's problem
+    fn check_defined(&self, defined: &[bool], ip: usize, range: Range<usize>) -> Result<(), InterpError> {
+        match range.into_iter().find(|i| !defined.get(*i).copied().unwrap_or(true)) {
+            Some(location) => Err(self.invalid(ip, Problem::Uninitialized(location))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn mark(defined: &mut [bool], marks: impl Iterator<Item = (Range<usize>, Vec<bool>)>) {
+    for (range, bytes) in marks {
+        // anything past the end of memory is about to be an OutOfRange anyway
+        for (byte, value) in defined.iter_mut().skip(range.start).zip(bytes).take(range.len()) {
+            *byte = value;
+        }
+    }
+}
+
+// the Q at base + offset
+fn slot(base: usize, offset: i32) -> Range<usize> {
+    let location = base.wrapping_add(offset as usize);
+    location..location.wrapping_add(8)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::instruction::{Count, Label, Signedness, Ordering};
+    use crate::interpreter_fn::test_util::{N, interpreter, invalid};
+    use super::*;
+
+    fn checked(instructions: Vec<Instruction>) -> InterpreterFn {
+        let mut interp = interpreter(instructions);
+        interp.set_checked(true);
+        interp
+    }
+
+    fn run(interp: &InterpreterFn) -> Result<u64, (usize, Problem)> {
+        interp.run(1, 2, 3, 4, 5, 6).map_err(invalid)
+    }
+
+    fn here(offset: i32) -> Src { Src::Here(offset, Size::Q) }
+
+    #[test]
+    fn args_are_defined_and_the_rest_of_the_frame_isnt() {
+        let add = |b| Instruction::AddChecked(Dest::Here(-24, Size::Q), here(-8), b, Signedness::Unsigned, Label(0));
+        let code = |b| vec![
+            Instruction::FFIBegin(32, [Dest::Here(-8, Size::Q), Dest::Here(-16, Size::D), N, N, N, N]),
+            add(b),
+            Instruction::Label(Label(0)),
+            Instruction::FFIRet(here(-24)),
+        ];
+        assert_eq!(run(&checked(code(Src::Here(-16, Size::D)))), Ok(3));
+        // the arg only defined the low half of its slot
+        assert_eq!(run(&checked(code(here(-16)))), Err((1, Problem::Uninitialized(1024 - 12))));
+        assert_eq!(run(&checked(code(here(-32)))), Err((1, Problem::Uninitialized(1024 - 32))));
+
+        // unchecked, it's just zero
+        let mut unchecked = checked(code(here(-32)));
+        unchecked.set_checked(false);
+        assert_eq!(run(&unchecked), Ok(1));
+    }
+
+    #[test]
+    fn copies_carry_definedness() {
+        // -8 and -24 are defined, -16 isn't; they're copied a byte at a time to -48..-24, and one of those is returned
+        let code = |ret| vec![
+            Instruction::FFIBegin(48, [Dest::Here(-8, Size::Q), N, N, N, N, N]),
+            Instruction::Copy(Dest::Here(-24, Size::Q), Src::Imm(7), Count(1)),
+            Instruction::Copy(Dest::Here(-48, Size::B), Src::Here(-24, Size::B), Count(24)),
+            Instruction::FFIRet(ret),
+        ];
+        assert_eq!(run(&checked(code(here(-48)))), Ok(7));
+        assert_eq!(run(&checked(code(here(-32)))), Ok(1));
+        assert_eq!(run(&checked(code(here(-40)))), Err((3, Problem::Uninitialized(1024 - 40))));
+
+        // each copy happens after the last, so undefinedness can spread along an overlapping one
+        let code = vec![
+            Instruction::FFIBegin(16, [Dest::Here(-8, Size::Q), N, N, N, N, N]),
+            Instruction::Copy(Dest::Here(-8, Size::B), Src::Here(-9, Size::B), Count(8)),
+            Instruction::FFIRet(Src::Here(-1, Size::B)),
+        ];
+        assert_eq!(run(&checked(code)), Err((2, Problem::Uninitialized(1024 - 1))));
+
+        // copying Uninitialized over something defined makes it undefined again
+        let code = vec![
+            Instruction::FFIBegin(16, [Dest::Here(-8, Size::Q), N, N, N, N, N]),
+            Instruction::Copy(Dest::Here(-8, Size::B), Src::Uninitialized, Count(1)),
+            Instruction::FFIRet(here(-8)),
+        ];
+        assert_eq!(run(&checked(code)), Err((2, Problem::Uninitialized(1024 - 8))));
+    }
+
+    #[test]
+    fn swaps_trade_definedness() {
+        // after the swap, the place has the arg's definedness and `old` has the place's
+        let code = |ret| vec![
+            Instruction::FFIBegin(24, [Dest::Here(-8, Size::Q), N, N, N, N, N]),
+            Instruction::Swap(Dest::Here(-24, Size::Q), Dest::Here(-16, Size::Q), here(-8), Ordering::SeqCst),
+            Instruction::FFIRet(ret),
+        ];
+        assert_eq!(run(&checked(code(here(-16)))), Ok(1));
+        assert_eq!(run(&checked(code(here(-24)))), Err((2, Problem::Uninitialized(1024 - 24))));
+    }
+
+    #[test]
+    fn following_pointers_needs_them_defined() {
+        let code = vec![
+            Instruction::FFIBegin(16, [N; 6]),
+            Instruction::JIf(Src::Ptr(-8, 0, Size::B), Label(0)),
+            Instruction::Label(Label(0)),
+            Instruction::FFIRet(Src::Imm(0)),
+        ];
+        assert_eq!(run(&checked(code)), Err((1, Problem::Uninitialized(1024 - 8))));
+    }
+}
@@ -0,0 +1,311 @@
+use std::hash::{Hash, Hasher};
+
+use crate::trap::{Trap, TrapCode};
+use super::{InterpreterFn, InterpError, State};
+
+// a run that stopped because its fuel ran out, which can be picked up again with InterpreterFn::resume --
+// later, or on another machine, since it's just bytes.
+// it holds everything the run can see: ip, bp, sp, the whole memory (so the globals as the run left them),
+// the args, the shadow in checked mode, and the sandbox's memory in sandbox mode.
+// it also has a hash of the code, so it can't be resumed by anything else
+pub struct Snapshot {
+    code_hash: u64,
+    state: State,
+    linear_memory: Option<Vec<u8>>,
+}
+
+pub enum Outcome {
+    Returned(u64),
+    Paused(Snapshot),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SnapshotError {
+    NotASnapshot,  // wrong magic number or version, or nonsense in the middle
+    Truncated,
+    TrailingBytes,
+    DifferentCode,
+    DifferentMode,  // checked or not, sandboxed or not
+    HostValues,  // a run that's stored what an FFICall returned can't be paused, since it could be a pointer
+}
+
+const MAGIC: &[u8; 8] = b"pdsnap02";
+
+impl InterpreterFn {
+    // like run, except that running out of fuel pauses the run instead of trapping.
+    // the globals are only kept for next time once the run gets to the end.
+    // not in host mode, where the stack is full of real addresses
+    pub fn run_pausable(&self, args: [u64; 6]) -> Result<Outcome, InterpError> {
+        assert!(!self.host_memory, "can't pause a run in host mode");
+        self.continue_run(self.start(args))
+    }
+
+    // carries on from a snapshot of a run of this same code, with a new tank of fuel
+    pub fn resume(&self, snapshot: Snapshot, fuel: u64) -> Result<Outcome, InterpError> {
+        assert!(!self.host_memory, "can't resume a run in host mode");
+        let Snapshot { code_hash, mut state, linear_memory } = snapshot;
+        let same_layout = state.bp == self.stack_size && state.memory.len() == self.memory_template.borrow().len();
+        if code_hash != self.code_hash() || !same_layout {
+            return Err(InterpError::Snapshot(SnapshotError::DifferentCode));
+        }
+        if state.defined.is_some() != self.checked || linear_memory.is_some() != self.linear_memory.is_some() {
+            return Err(InterpError::Snapshot(SnapshotError::DifferentMode));
+        }
+
+        if let (Some(memory), Some(saved)) = (&self.linear_memory, linear_memory) {
+            *memory.borrow_mut() = saved;
+        }
+        state.fuel = fuel;
+        self.continue_run(state)
+    }
+
+    fn continue_run(&self, mut state: State) -> Result<Outcome, InterpError> {
+        let result = loop {
+            match self.step(&mut state) {
+                Ok(None) => {}
+                Ok(Some(value)) => break Ok(Outcome::Returned(value)),
+                // step checks the fuel before doing anything else, so `state` is still just before the instruction at ip
+                Err(InterpError::Trap(Trap { code: TrapCode::OutOfFuel, .. })) if !state.host_values => {
+                    let linear_memory = self.linear_memory.as_ref().map(|memory| memory.borrow().clone());
+                    return Ok(Outcome::Paused(Snapshot { code_hash: self.code_hash(), state, linear_memory }));
+                }
+                Err(InterpError::Trap(Trap { code: TrapCode::OutOfFuel, .. })) => break Err(InterpError::Snapshot(SnapshotError::HostValues)),
+                Err(error) => break Err(error),
+            }
+        };
+        self.finish(&state);
+        result
+    }
+
+    // FNV-1a, so it's the same everywhere
+    fn code_hash(&self) -> u64 {
+        struct Fnv(u64);
+        impl Hasher for Fnv {
+            fn finish(&self) -> u64 { self.0 }
+            fn write(&mut self, bytes: &[u8]) {
+                for byte in bytes {
+                    self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100_0000_01b3);
+                }
+            }
+        }
+        let mut hasher = Fnv(0xcbf2_9ce4_8422_2325);
+        self.code.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Snapshot {
+    // the index of the instruction that'll run first after resuming
+    pub fn ip(&self) -> usize {
+        self.state.ip
+    }
+
+    // little-endian: the magic number, the code's hash, ip, bp, sp and the args, then the memory as a length and its bytes,
+    // then the shadow and the sandbox's memory the same way, each behind a flag byte
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let State { ip, bp, sp, fuel: _, ref memory, ref defined, args, host_values: _ } = self.state;
+
+        let mut bytes = MAGIC.to_vec();
+        for value in [self.code_hash, ip as u64, bp as u64, sp as u64].iter().chain(args.iter()) {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        write_bytes(&mut bytes, memory);
+        match defined {
+            Some(defined) => {
+                bytes.push(1);
+                write_bytes(&mut bytes, &defined.iter().map(|byte| *byte as u8).collect::<Vec<_>>());
+            }
+            None => bytes.push(0),
+        }
+        match &self.linear_memory {
+            Some(memory) => {
+                bytes.push(1);
+                write_bytes(&mut bytes, memory);
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let code_hash = reader.u64()?;
+        let ip = reader.u64()? as usize;
+        let bp = reader.u64()? as usize;
+        let sp = reader.u64()? as usize;
+        let mut args = [0; 6];
+        for arg in args.iter_mut() {
+            *arg = reader.u64()?;
+        }
+        let memory = reader.bytes()?.to_vec();
+        let defined = match reader.flag()? {
+            true => Some(reader.bytes()?.iter().map(|byte| *byte != 0).collect::<Vec<_>>()),
+            false => None,
+        };
+        let linear_memory = match reader.flag()? {
+            true => Some(reader.bytes()?.to_vec()),
+            false => None,
+        };
+        if !reader.bytes.is_empty() {
+            return Err(SnapshotError::TrailingBytes);
+        }
+        if bp > memory.len() || sp > bp || defined.as_ref().is_some_and(|defined| defined.len() != memory.len()) {
+            return Err(SnapshotError::NotASnapshot);
+        }
+
+        // the fuel gets replaced by resume anyway
+        let state = State { ip, bp, sp, fuel: 0, memory, defined, args, host_values: false };
+        Ok(Snapshot { code_hash, state, linear_memory })
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if n > self.bytes.len() {
+            return Err(SnapshotError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().expect("took 8 bytes")))
+    }
+
+    fn flag(&mut self) -> Result<bool, SnapshotError> {
+        match self.take(1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::NotASnapshot),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let n = self.u64()?;
+        self.take(usize::try_from(n).map_err(|_| SnapshotError::Truncated)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::instruction::{Instruction, Dest, Src, Size, Count, Label, Signedness, FFIFunction};
+    use crate::interpreter_fn::test_util::{N, interpreter};
+    use super::*;
+
+    // 1 + 2 + .. + (arg0 - 1), with an FFICall to `f` (storing to `dest`) on the way in
+    fn sum(dest: Dest) -> Vec<Instruction> {
+        extern "C" fn f(_: u64, _: u64, _: u64, _: u64, _: u64, _: u64) -> u64 { 0x7fff_1234_0000 }
+        vec![
+            Instruction::FFIBegin(24, [Dest::Here(-8, Size::Q), N, N, N, N, N]),
+            Instruction::Copy(Dest::Here(-16, Size::Q), Src::Imm(0), Count(1)),
+            Instruction::FFICall(dest, [Src::Imm(0); 6], FFIFunction::new("f", f)),
+            Instruction::Label(Label(0)),
+            Instruction::SubChecked(Dest::Here(-8, Size::Q), Src::Here(-8, Size::Q), Src::Imm(1), Signedness::Unsigned, Label(1)),
+            Instruction::AddChecked(Dest::Here(-16, Size::Q), Src::Here(-16, Size::Q), Src::Here(-8, Size::Q), Signedness::Unsigned, Label(1)),
+            Instruction::JIf(Src::Imm(1), Label(0)),
+            Instruction::Label(Label(1)),
+            Instruction::FFIRet(Src::Here(-16, Size::Q)),
+        ]
+    }
+
+    fn paused(outcome: Result<Outcome, InterpError>) -> Snapshot {
+        match outcome {
+            Ok(Outcome::Paused(snapshot)) => snapshot,
+            Ok(Outcome::Returned(value)) => panic!("returned {}", value),
+            Err(error) => panic!("{:?}", error),
+        }
+    }
+
+    fn snapshot_error(outcome: Result<Outcome, InterpError>) -> SnapshotError {
+        match outcome {
+            Err(InterpError::Snapshot(error)) => error,
+            Err(error) => panic!("{:?}", error),
+            Ok(_) => panic!("it ran"),
+        }
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let mut whole = interpreter(sum(N));
+        whole.set_profiling(true);
+        let ips = Rc::new(RefCell::new(vec![]));
+        let sink = ips.clone();
+        whole.set_trace(move |entry| sink.borrow_mut().push(entry.ip));
+        assert_eq!(whole.run(10, 0, 0, 0, 0, 0).unwrap(), 45);
+
+        // pausing every 7 instructions, going through bytes each time, makes no difference to what ran
+        let mut pausing = interpreter(sum(N));
+        pausing.set_profiling(true);
+        let paused_ips = Rc::new(RefCell::new(vec![]));
+        let sink = paused_ips.clone();
+        pausing.set_trace(move |entry| sink.borrow_mut().push(entry.ip));
+        pausing.set_fuel(7);
+        let mut outcome = pausing.run_pausable([10, 0, 0, 0, 0, 0]);
+        let n_pauses = loop {
+            match outcome {
+                Ok(Outcome::Paused(snapshot)) => {
+                    let snapshot = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+                    outcome = pausing.resume(snapshot, 7);
+                }
+                Ok(Outcome::Returned(value)) => { assert_eq!(value, 45); break paused_ips.borrow().len() / 7 }
+                Err(error) => panic!("{:?}", error),
+            }
+        };
+        assert!(n_pauses > 3);
+        assert_eq!(*paused_ips.borrow(), *ips.borrow());
+        assert_eq!(pausing.instruction_counts().unwrap(), whole.instruction_counts().unwrap());
+    }
+
+    #[test]
+    fn only_the_same_code_in_the_same_mode() {
+        let mut interp = interpreter(sum(N));
+        interp.set_fuel(5);
+        let snapshot = || paused(interp.run_pausable([10, 0, 0, 0, 0, 0])).to_bytes();
+
+        let mut different = sum(N);
+        different[1] = Instruction::Copy(Dest::Here(-16, Size::Q), Src::Imm(1), Count(1));
+        let snapshot_error = |interp: InterpreterFn| super::tests::snapshot_error(interp.resume(Snapshot::from_bytes(&snapshot()).unwrap(), 100));
+        assert_eq!(snapshot_error(interpreter(different)), SnapshotError::DifferentCode);
+
+        let mut checked = interpreter(sum(N));
+        checked.set_checked(true);
+        assert_eq!(snapshot_error(checked), SnapshotError::DifferentMode);
+        let mut sandboxed = interpreter(sum(N));
+        sandboxed.set_memory(vec![0; 16]);
+        assert_eq!(snapshot_error(sandboxed), SnapshotError::DifferentMode);
+
+        let bytes = snapshot();
+        assert_eq!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).err(), Some(SnapshotError::Truncated));
+        assert_eq!(Snapshot::from_bytes(&[bytes.as_slice(), &[0]].concat()).err(), Some(SnapshotError::TrailingBytes));
+        assert_eq!(Snapshot::from_bytes(b"pdsnap01").err(), Some(SnapshotError::NotASnapshot));
+    }
+
+    #[test]
+    fn no_host_values() {
+        let mut interp = interpreter(sum(Dest::Here(-24, Size::Q)));
+        interp.set_fuel(5);
+        assert_eq!(snapshot_error(interp.run_pausable([10, 0, 0, 0, 0, 0])), SnapshotError::HostValues);
+
+        // a mock's results aren't from the host, and neither is a result that's thrown away
+        interp.mock_returns("f", vec![3]);
+        paused(interp.run_pausable([10, 0, 0, 0, 0, 0]));
+        let mut interp = interpreter(sum(N));
+        interp.set_fuel(5);
+        paused(interp.run_pausable([10, 0, 0, 0, 0, 0]));
+    }
+}
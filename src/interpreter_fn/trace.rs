@@ -0,0 +1,96 @@
+use std::fmt;
+
+use crate::instruction::{Instruction, Src, Dest};
+
+// what one instruction did: every value it read from memory and every value it stored
+#[derive(Clone, Debug)]
+pub struct TraceEntry {
+    pub ip: usize,
+    pub instruction: Option<Instruction>,  // None if ip isn't in the code at all
+    pub loads: Vec<(Src, u64)>,
+    pub stores: Vec<(Dest, u64)>,
+}
+
+pub(super) type Sink = Box<dyn FnMut(&TraceEntry)>;
+
+pub(super) struct Tracer {
+    pub sink: Option<Sink>,  // taken out while it's being called, and nothing's traced meanwhile
+    pub current: Vec<TraceEntry>,  // the instructions that are running: more than one when a mock runs the interpreter
+}
+
+//    3 Copy(Here(-8, Q), Here(-16, Q), Count(1))  Here(-16, Q) = 0x5  -> Here(-8, Q) = 0x5
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.instruction {
+            Some(instruction) => write!(f, "{:4} {:?}", self.ip, instruction)?,
+            None => write!(f, "{:4} (outside the code)", self.ip)?,
+        }
+        for (i, (src, value)) in self.loads.iter().enumerate() {
+            let separator = if i == 0 { "  " } else { ", " };
+            write!(f, "{}{:?} = {:#x}", separator, src, value)?;
+        }
+        for (i, (dest, value)) in self.stores.iter().enumerate() {
+            let separator = if i == 0 { "  -> " } else { ", " };
+            write!(f, "{}{:?} = {:#x}", separator, dest, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::instruction::{Count, Size::{B, Q}};
+    use crate::interpreter_fn::InterpreterFn;
+    use crate::interpreter_fn::test_util::{N, interpreter};
+    use super::*;
+
+    fn copies() -> InterpreterFn {
+        interpreter(vec![
+            Instruction::FFIBegin(16, [Dest::Here(-8, Q), N, N, N, N, N]),
+            Instruction::Copy(Dest::Here(-16, Q), Src::Here(-8, Q), Count(1)),
+            Instruction::Copy(Dest::Here(-12, B), Src::Imm(0xab), Count(2)),
+            Instruction::FFIRet(Src::Here(-16, Q)),
+        ])
+    }
+
+    #[test]
+    fn loads_and_stores() {
+        let entries = Rc::new(RefCell::new(vec![]));
+        let mut interp = copies();
+        interp.set_trace({
+            let entries = entries.clone();
+            move |entry| entries.borrow_mut().push(entry.clone())
+        });
+        assert_eq!(interp.run(0x1122_3344_5566_7788, 0, 0, 0, 0, 0).unwrap(), 0x1122_abab_5566_7788);
+
+        let entries = entries.borrow();
+        assert_eq!(entries.iter().map(|entry| entry.ip).collect::<Vec<_>>(), [0, 1, 2, 3]);
+        assert_eq!(entries[1].to_string(), "   1 Copy(Here(-16, Q), Here(-8, Q), Count(1))  Here(-8, Q) = 0x1122334455667788  -> Here(-16, Q) = 0x1122334455667788");
+        assert_eq!(entries[2].to_string(), "   2 Copy(Here(-12, B), Imm(171), Count(2))  -> Here(-12, B) = 0xab, Here(-11, B) = 0xab");
+        assert_eq!(entries[3].to_string(), "   3 FFIRet(Here(-16, Q))  Here(-16, Q) = 0x1122abab55667788");
+    }
+
+    #[test]
+    fn sinks_can_run_the_interpreter() {
+        // the sink runs the same interpreter again for every instruction, but those runs aren't traced
+        let interp = Rc::new(RefCell::new(None::<Rc<InterpreterFn>>));
+        let ips = Rc::new(RefCell::new(vec![]));
+        let mut traced = copies();
+        traced.set_trace({
+            let (interp, ips) = (interp.clone(), ips.clone());
+            move |entry| {
+                let inner = interp.borrow().clone().unwrap();
+                assert_eq!(inner.run(5, 0, 0, 0, 0, 0).unwrap(), 0xabab_0000_0005);
+                ips.borrow_mut().push(entry.ip);
+            }
+        });
+        let traced = Rc::new(traced);
+        *interp.borrow_mut() = Some(traced.clone());
+
+        assert_eq!(traced.run(5, 0, 0, 0, 0, 0).unwrap(), 0xabab_0000_0005);
+        assert_eq!(*ips.borrow(), [0, 1, 2, 3]);
+        interp.borrow_mut().take();
+    }
+}
@@ -1,8 +1,16 @@
+use std::{cell::RefCell, ops::Range, sync::{Arc, atomic::{AtomicBool, Ordering}}};
+
+use crate::{codegen::{Image, Runtime}, instruction::GlobalId, object, trap::{Trap, TrapCode}};
+
 // source: https://make-a-demo-tool-in-rust.github.io/1-3-jit.html
 const PAGE_SIZE: usize = 4096;  // OS X constraint, must be aligned to 0x1000
 
 pub struct JitFn<Arg: Copy, Ret: Copy> {
     addr: *mut u8,
+    globals: Vec<Range<usize>>,  // offsets from `addr`
+    runtime: Option<usize>,
+    fuel: u64,  // only spent by code generated with Options::fuel
+    memory: RefCell<Vec<u8>>,  // the linear memory, for code generated with Options::sandbox
 
     m_arg: std::marker::PhantomData<*const Arg>,
     m_ret: std::marker::PhantomData<*const Ret>,
@@ -12,37 +20,156 @@ pub struct JitFn<Arg: Copy, Ret: Copy> {
 impl<Arg: Copy, Ret: Copy> JitFn<Arg, Ret> {
     // NOTE: get_bytes takes a base address
     // and must always produce code of the same length regardless of its argument
-    pub fn new(get_bytes: impl Fn(*mut u8) -> Vec<u8>) -> Self {
-        use std::mem;
-        
-        let bytes_1 = get_bytes(std::ptr::null_mut());
-        let desired_pages = (bytes_1.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+    pub fn new<I: Into<Image>>(get_bytes: impl Fn(*mut u8) -> I) -> Self {
+        let bytes_1 = get_bytes(std::ptr::null_mut()).into().bytes;
 
         unsafe {
-            let raw_addr: *mut winapi::ctypes::c_void;
-
-            raw_addr = winapi::um::memoryapi::VirtualAlloc(
-                std::ptr::null_mut(),
-                desired_pages * PAGE_SIZE,
-                winapi::um::winnt::MEM_RESERVE | winapi::um::winnt::MEM_COMMIT,
-                winapi::um::winnt::PAGE_EXECUTE_READWRITE
-            );
-            
-            let addr: *mut u8 = mem::transmute(raw_addr);
-            let bytes_2 = get_bytes(addr);
+            let addr = allocate(bytes_1.len());
+            let image = get_bytes(addr).into();
+            let bytes_2 = image.bytes;
             assert!(bytes_2.len() == bytes_1.len(), "length should be the same no matter what");
             std::ptr::copy_nonoverlapping(bytes_2.as_ptr(), addr, bytes_2.len());
+            protect(addr, image.read_only);
+
+            Self { addr, globals: image.globals, runtime: image.runtime, fuel: u64::MAX, memory: RefCell::new(vec![]), m_arg: std::marker::PhantomData, m_ret: std::marker::PhantomData }
+        }
+    }
+
+    // for position-independent code, which only needs generating once
+    pub fn from_image(image: Image) -> Self {
+        assert!(image.position_independent, "code that depends on its base address has to go through JitFn::new");
+
+        unsafe {
+            let addr = allocate(image.bytes.len());
+            std::ptr::copy_nonoverlapping(image.bytes.as_ptr(), addr, image.bytes.len());
+            protect(addr, image.read_only);
+
+            Self { addr, globals: image.globals, runtime: image.runtime, fuel: u64::MAX, memory: RefCell::new(vec![]), m_arg: std::marker::PhantomData, m_ret: std::marker::PhantomData }
+        }
+    }
+
+    // (looking at the image from its start up to the end of the last global)
+    pub fn read_global(&self, global: GlobalId) -> Vec<u8> {
+        let image = unsafe { std::slice::from_raw_parts(self.addr, self.globals_end()) };
+        object::read_global(&self.globals, image, global)
+    }
+
+    pub fn write_global(&self, global: GlobalId, bytes: &[u8]) {
+        let image = unsafe { std::slice::from_raw_parts_mut(self.addr, self.globals_end()) };
+        object::write_global(&self.globals, image, global, bytes)
+    }
+
+    fn globals_end(&self) -> usize {
+        self.globals.last().map_or(0, |global| global.end)
+    }
+
+    // how many labels (and entries) a run may pass before it traps with OutOfFuel.
+    // the interpreter charges per instruction instead, so the same fuel runs out sooner there
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = fuel;
+    }
+
+    // what the sandbox's pointers point into. it keeps its contents between runs
+    pub fn set_memory(&mut self, memory: Vec<u8>) {
+        self.memory = RefCell::new(memory);
+    }
 
-            Self { addr, m_arg: std::marker::PhantomData, m_ret: std::marker::PhantomData }
+    pub fn read_memory(&self) -> Vec<u8> {
+        self.memory.borrow().clone()
+    }
+
+    pub unsafe fn run(&self, arg: Arg) -> Result<Ret, Trap> {
+        self.enter(&AtomicBool::new(false), |addr| {
+            let ptr: extern fn(Arg) -> Ret = std::mem::transmute(addr);
+            ptr(arg)
+        })
+    }
+
+    // what the IR really takes, whatever Arg and Ret say
+    pub unsafe fn run_with_args(&self, args: [u64; 6]) -> Result<u64, Trap> {
+        self.enter(&AtomicBool::new(false), |addr| {
+            let ptr: extern fn(u64, u64, u64, u64, u64, u64) -> u64 = std::mem::transmute(addr);
+            ptr(args[0], args[1], args[2], args[3], args[4], args[5])
+        })
+    }
+
+    // like run, but first gives `with_handle` a way to stop this run from another thread, which traps with
+    // Interrupted at the next label (code generated with Options::interruptible checks for it).
+    // the handle only ever stops this run: once it's over, interrupting does nothing, so a late timeout can't
+    // stop some other run. interrupting before the code starts stops it as soon as it's entered
+    pub unsafe fn run_with_handle(&self, arg: Arg, with_handle: impl FnOnce(InterruptHandle)) -> Result<Ret, Trap> {
+        let interrupt = Arc::new(AtomicBool::new(false));
+        with_handle(InterruptHandle(interrupt.clone()));
+        self.enter(&interrupt, |addr| {
+            let ptr: extern fn(Arg) -> Ret = std::mem::transmute(addr);
+            ptr(arg)
+        })
+    }
+
+    // sets up the Runtime, calls the code, and checks whether it trapped
+    unsafe fn enter<R>(&self, interrupt: &AtomicBool, call: impl FnOnce(*mut u8) -> R) -> Result<R, Trap> {
+        let runtime = self.runtime.map(|offset| self.addr.add(offset) as *mut Runtime);
+        let mut memory = self.memory.borrow_mut();
+        if let Some(runtime) = runtime {
+            (*runtime).trap_kind = 0;
+            (*runtime).fuel = self.fuel;
+            (*runtime).interrupt = interrupt.as_ptr() as u64;
+            (*runtime).memory = memory.as_mut_ptr() as u64;
+            (*runtime).memory_size = memory.len() as u64;
+        }
+
+        let ret = call(self.addr);
+
+        if let Some(runtime) = runtime {
+            if let Some(code) = TrapCode::from_raw((*runtime).trap_kind, (*runtime).trap_payload) {
+                return Err(Trap { code, ir_index: (*runtime).trap_index as usize })
+            }
         }
+        Ok(ret)
     }
+}
 
-    pub unsafe fn run(&self, arg: Arg) -> Ret {
-        let ptr: extern fn(Arg) -> Ret = std::mem::transmute(self.addr);
-        ptr(arg)
+#[derive(Clone)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
     }
 }
 
+#[cfg(target_os = "windows")]
+unsafe fn allocate(n_bytes: usize) -> *mut u8 {
+    let desired_pages = (n_bytes + PAGE_SIZE - 1) / PAGE_SIZE;
+
+    let raw_addr: *mut winapi::ctypes::c_void;
+
+    raw_addr = winapi::um::memoryapi::VirtualAlloc(
+        std::ptr::null_mut(),
+        desired_pages * PAGE_SIZE,
+        winapi::um::winnt::MEM_RESERVE | winapi::um::winnt::MEM_COMMIT,
+        winapi::um::winnt::PAGE_EXECUTE_READWRITE
+    );
+
+    std::mem::transmute(raw_addr)
+}
+
+// once the bytes are in, the data can't be written to any more (it's on whole pages of its own)
+#[cfg(target_os = "windows")]
+unsafe fn protect(addr: *mut u8, read_only: Range<usize>) {
+    if read_only.is_empty() { return }
+
+    let mut old_protect = 0;
+    let result = winapi::um::memoryapi::VirtualProtect(
+        addr.add(read_only.start).cast(),
+        read_only.len(),
+        winapi::um::winnt::PAGE_READONLY,
+        &mut old_protect
+    );
+
+    if result == 0 { panic!("VirtualProtect returned 0") }
+}
+
 #[cfg(target_os = "windows")]
 impl<Arg: Copy, Ret: Copy> Drop for JitFn<Arg, Ret> {
     fn drop(&mut self) {
@@ -4,4 +4,4 @@ mod impl_windows;
 #[cfg(target_os = "windows")]
 use impl_windows as implementation;
 
-pub use implementation::JitFn;
\ No newline at end of file
+pub use implementation::{JitFn, InterruptHandle};
\ No newline at end of file
@@ -5,14 +5,44 @@ use pretty_hex::*;
 
 use crate::{jit_fn::JitFn, instruction::{Instruction, Dest, Src, Count}, interpreter_fn::InterpreterFn};
 
+mod bench;
 mod codegen;
+mod debugger;
+mod differential;
 mod instruction;
 mod interpreter_fn;
 mod jit_fn;
 mod object;
 mod parser;
+mod trap;
+
+// for the subcommands: says what went wrong and exits non-zero
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1)
+}
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if let Some("debug") = args.get(1).map(String::as_str) {
+        match args.get(2) {
+            Some(path) => debugger::debug(path, &args[3..]),
+            None => fail("usage: pinkdrone debug file.pd [args...]".to_string()),
+        }
+        return;
+    }
+    if let Some("diff") = args.get(1).map(String::as_str) {
+        match args.get(2) {
+            Some(path) => differential::diff(path, args.get(3)),
+            None => fail("usage: pinkdrone diff file.pd [number of arg vectors]".to_string()),
+        }
+        return;
+    }
+    if let Some("bench") = args.get(1).map(String::as_str) {
+        bench::bench();
+        return;
+    }
+
     // TODO: Support hex literals again
     let proc = crate::parser::parse("
         ffinyeh 0x10 ().
@@ -41,17 +71,21 @@ fn main() {
     return;
 
     let proc = proc.unwrap();
-    println!("code:\n{:?}", proc.codegen(0).hex_dump());
+    println!("code:\n{:?}", proc.codegen(0).unwrap().bytes.hex_dump());
 
-    let jit_bat: JitFn<(), u64> = JitFn::new(|addr| proc.codegen(addr as u64));
+    let jit_bat: JitFn<(), u64> = JitFn::new(|addr| proc.codegen(addr as u64).unwrap());
 
-    let bat = unsafe { jit_bat.run(()) };
-    println!("QUAKE, MORTAL. IT IS I, {:#010X}", bat);
+    match unsafe { jit_bat.run(()) } {
+        Ok(bat) => println!("QUAKE, MORTAL. IT IS I, {:#010X}", bat),
+        Err(trap) => println!("the JIT trapped: {:?}", trap),
+    }
 
-    let interpret_bat: InterpreterFn = InterpreterFn::new(proc.instructions, 1024);
+    let interpret_bat: InterpreterFn = InterpreterFn::new(proc, 1024);
 
-    let bat = interpret_bat.run(0, 0, 0, 0, 0, 0);
-    println!("QUAKE, MORTAL. IT IS I, {:#010X}", bat);
+    match interpret_bat.run(0, 0, 0, 0, 0, 0) {
+        Ok(bat) => println!("QUAKE, MORTAL. IT IS I, {:#010X}", bat),
+        Err(error) => println!("the interpreter stopped: {:?}", error),
+    }
 
     
     /*
@@ -64,7 +98,7 @@ fn main() {
         0xc3,
     ]);
 
-    let bat = unsafe { dead_bat.run(()) };
+    let bat = unsafe { dead_bat.run(()) }.unwrap();
     println!("QUAKE, MORTAL. IT IS I, {:#010X}", bat);
     */
 }
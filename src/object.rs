@@ -1,16 +1,66 @@
-use crate::{instruction::Instruction, codegen::Codegen};
+use std::ops::Range;
+
+use crate::{instruction::{Instruction, GlobalId}, codegen::{Codegen, CodegenError, Image, Options}};
 
 #[derive(Debug)]
 pub struct Object {
     pub instructions: Vec<Instruction>,
+    pub data: Vec<Blob>,  // Src::DataAddr(DataId(i)) is the address of data[i]
+    pub globals: Vec<Blob>,  // Src::Global(GlobalId(i), ..) reads from globals[i], which starts out as its bytes
+}
+
+// named bytes that get placed after the code
+// data is read-only (JitFn protects its pages, and the interpreter won't store to it) -- that's what globals are for
+#[derive(Clone, Debug)]
+pub struct Blob {
+    pub name: String,
+    pub align: u64,
+    pub bytes: Vec<u8>,
 }
 
 impl Object {
-    pub fn codegen(&self, base_address: u64) -> Vec<u8> {
-        let mut codegen = Codegen::new(base_address);
+    pub fn codegen(&self, base_address: u64) -> Result<Image, CodegenError> {
+        self.codegen_with(base_address, Options::default())
+    }
+
+    // the base address doesn't matter for PIC
+    pub fn codegen_pic(&self) -> Result<Image, CodegenError> {
+        self.codegen_with(0, Options { pic: true, ..Options::default() })
+    }
+
+    pub fn codegen_with(&self, base_address: u64, options: Options) -> Result<Image, CodegenError> {
+        let mut codegen = Codegen::with_options(base_address, options);
         for inst in self.instructions.iter() {
-            codegen.write(*inst);
+            codegen.write(inst.clone());
         }
-        codegen.finalize()
+        codegen.finalize(&self.data, &self.globals)
+    }
+
+    pub fn global(&self, name: &str) -> Option<GlobalId> {
+        self.globals.iter().position(|g| g.name == name).map(GlobalId)
     }
-}
\ No newline at end of file
+}
+
+// where each blob ends up, relative to `start` (which is assumed to be suitably aligned)
+pub(crate) fn layout(start: usize, blobs: &[Blob]) -> (Vec<usize>, usize) {
+    let mut locations = vec![];
+    let mut end = start;
+    for blob in blobs {
+        let align = blob.align.max(1) as usize;
+        let location = end.div_ceil(align) * align;
+        locations.push(location);
+        end = location + blob.bytes.len();
+    }
+    (locations, end)
+}
+
+// globals keep their values between runs: these get at them where an engine keeps them, in `memory` at `locations`
+pub(crate) fn read_global(locations: &[Range<usize>], memory: &[u8], global: GlobalId) -> Vec<u8> {
+    memory[locations[global.0].clone()].to_vec()
+}
+
+pub(crate) fn write_global(locations: &[Range<usize>], memory: &mut [u8], global: GlobalId, bytes: &[u8]) {
+    let range = locations[global.0].clone();
+    assert!(bytes.len() == range.len(), "global is {} bytes, not {}", range.len(), bytes.len());
+    memory[range].clone_from_slice(bytes);
+}
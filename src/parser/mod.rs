@@ -2,25 +2,32 @@ use std::ops::Range;
 
 use chumsky::{prelude::*, combinator::DelimitedBy, Stream};
 
-use crate::{object::Object, instruction::{Instruction, Dest, Size, Src, Count}};
+use crate::{object::{Object, Blob}, instruction::{Instruction, Dest, Size, Src, Count, Label, DataId, GlobalId}};
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 enum Token {
     KWFFIRet, 
     KWCopy, 
     KWJif, KWJmp,
+    KWLabel,
+    KWBp,
 
     KWFFIBegin,  // spelled "ffinyeh"
     KWFFICall,
 
+    KWData, KWGlobal, KWAlign,
+
     LParen, RParen, LBrack, RBrack,
-    Arrow, Minus, Plus, Colon, Comma, Dot, Underscore,
+    Arrow, Minus, Plus, Colon, Comma, Dot, Underscore, Equals,
     Number(u64),
+    Str(String),
     Identifier(String),
 }
 
 enum Sign { Minus, Plus }
 
+enum Item { Instruction(Instruction), Data(Blob), Global(Blob) }
+
 type Span = Range<usize>;
 type Spanned<T> = (T, Span);
 
@@ -28,7 +35,8 @@ pub fn parse(code: &str) -> Result<Spanned<Object>, String> {
     let (tokens, errs) = lexer().then_ignore(end()).parse_recovery(code);
     let (object, parse_errs) = if let Some(tokens) = tokens {
         let len = code.chars().count(); // TODO: What's this bit do?
-        parser().then_ignore(end()).parse_recovery(Stream::from_iter(len..len + 1, tokens.into_iter()))
+        let (data, globals) = (declared(&tokens, Token::KWData), declared(&tokens, Token::KWGlobal));
+        parser(data, globals).then_ignore(end()).parse_recovery(Stream::from_iter(len..len + 1, tokens.into_iter()))
 
     } else {
         (None, vec![])
@@ -58,13 +66,37 @@ fn lexer() -> impl Parser<char, Vec<Spanned<Token>>, Error=Simple<char>> + Clone
 
     let comment = just("%").then(take_until(just('\n'))).padded();
 
+    let escape = just('\\').ignore_then(choice((
+        just('\\'),
+        just('"'),
+        just('n').to('\n'),
+        just('t').to('\t'),
+        just('0').to('\0'),
+    )));
+
+    let string = just('"')
+        .ignore_then(filter(|c| *c != '\\' && *c != '"').or(escape).repeated())
+        .then_ignore(just('"'))
+        .collect::<String>();
+
+    // words are lexed whole, so names can start with a keyword (like "labels" or "database")
+    let word = text::ident().map(|s: String| match s.as_str() {
+        "ffinyeh" => Token::KWFFIBegin,
+        "ffiret" => Token::KWFFIRet,
+        "fficall" => Token::KWFFICall,
+        "copy" => Token::KWCopy,
+        "jif" => Token::KWJif,
+        "jmp" => Token::KWJmp,
+        "label" => Token::KWLabel,
+        "bp" => Token::KWBp,
+        "data" => Token::KWData,
+        "global" => Token::KWGlobal,
+        "align" => Token::KWAlign,
+        "_" => Token::Underscore,
+        _ => Token::Identifier(s),
+    });
+
     return choice((
-        just("ffinyeh").map(|_| Token::KWFFIBegin),
-        just("ffiret").map(|_| Token::KWFFIRet),
-        just("copy").map(|_| Token::KWCopy),
-        just("jif").map(|_| Token::KWJif),
-        just("jmp").map(|_| Token::KWJmp),
-        just("fficall").map(|_| Token::KWFFICall),
         just("(").map(|_| Token::LParen),
         just(")").map(|_| Token::RParen),
         just("[").map(|_| Token::LBrack),
@@ -75,9 +107,10 @@ fn lexer() -> impl Parser<char, Vec<Spanned<Token>>, Error=Simple<char>> + Clone
         just(":").map(|_| Token::Colon),
         just(",").map(|_| Token::Comma),
         just(".").map(|_| Token::Dot),
-        just("_").map(|_| Token::Underscore),
+        just("=").map(|_| Token::Equals),
         number.map(|i| Token::Number(i)),
-        text::ident().map(|s: String| Token::Identifier(s)),
+        string.map(|s| Token::Str(s)),
+        word,
     ))
         .map_with_span(|tok, span| (tok, span))
         .padded_by(comment.repeated())
@@ -85,7 +118,24 @@ fn lexer() -> impl Parser<char, Vec<Spanned<Token>>, Error=Simple<char>> + Clone
         .repeated()
 }
 
-fn parser() -> impl Parser<Token, Spanned<Object>, Error=Simple<Token>> + Clone {
+// the names of the data or globals, in the order they're declared (so operands can refer to ones declared later)
+fn declared(tokens: &[Spanned<Token>], keyword: Token) -> Vec<String> {
+    let mut names = vec![];
+    for i in 0..tokens.len().saturating_sub(1) {
+        let starts_item = i == 0 || tokens[i - 1].0 == Token::Dot;
+        if let (true, true, Token::Identifier(name)) = (starts_item, tokens[i].0 == keyword, &tokens[i + 1].0) {
+            names.push(name.clone());
+        }
+    }
+    names
+}
+
+fn offset(sign: Sign, n: u64, span: Span) -> Result<i32, Simple<Token>> {
+    let offset = i32::try_from(n).map_err(|_| Simple::custom(span, "offsets have to fit in 32 bits"))?;
+    Ok(match sign { Sign::Minus => -offset, Sign::Plus => offset })
+}
+
+fn parser(data: Vec<String>, globals: Vec<String>) -> impl Parser<Token, Spanned<Object>, Error=Simple<Token>> + Clone {
     let signed_number = 
         choice((just(Token::Minus), just(Token::Plus))).or_not()
         .then(select! { Token::Number(u) => u })
@@ -97,10 +147,51 @@ fn parser() -> impl Parser<Token, Spanned<Object>, Error=Simple<Token>> + Clone
             }, num)
         );
 
-    let dest = just(Token::Underscore).map(|_| Dest::Nowhere);
+    let size = select! {
+        Token::Identifier(s) if s == "B" => Size::B,
+        Token::Identifier(s) if s == "H" => Size::H,
+        Token::Identifier(s) if s == "D" => Size::D,
+        Token::Identifier(s) if s == "Q" => Size::Q,
+    };
+
+    // bp-8 Q
+    let here = just(Token::KWBp).ignore_then(signed_number.clone()).then(size)
+        .try_map(|((sign, n), sz), span: Span| Ok((offset(sign, n, span)?, sz)));
+
+    // global count Q
+    // global table+8 D
+    let in_global = just(Token::KWGlobal).ignore_then(select! { Token::Identifier(name) => name })
+        .then(signed_number.clone().or_not()).then(size)
+        .try_map(move |((name, n), sz), span: Span| {
+            let id = globals.iter().position(|g| *g == name).ok_or_else(|| Simple::custom(span.clone(), format!("no global called {}", name)))?;
+            let (sign, n) = n.unwrap_or((Sign::Plus, 0));
+            Ok((GlobalId(id), offset(sign, n, span)?, sz))
+        });
+
+    // data message (its address)
+    let data_addr = just(Token::KWData).ignore_then(select! { Token::Identifier(name) => name })
+        .try_map(move |name, span: Span| {
+            data.iter().position(|d| *d == name).map(DataId).ok_or_else(|| Simple::custom(span, format!("no data called {}", name)))
+        });
+
+    let dest = choice((
+        just(Token::Underscore).map(|_| Dest::Nowhere),
+        here.clone().map(|(offset, sz)| Dest::Here(offset, sz)),
+        in_global.clone().map(|(id, offset, sz)| Dest::Global(id, offset, sz)),
+    ));
+
+    // a place, the address of some data, or a number (negative ones wrap around)
+    let src = choice((
+        here.map(|(offset, sz)| Src::Here(offset, sz)),
+        in_global.map(|(id, offset, sz)| Src::Global(id, offset, sz)),
+        data_addr.map(Src::DataAddr),
+        signed_number.clone().map(|(sign, n)| Src::Imm(match sign { Sign::Minus => n.wrapping_neg(), Sign::Plus => n })),
+    ));
+
+    let label = select! { Token::Number(n) => Label(n) };
 
     let ffi_begin = just(Token::KWFFIBegin).ignore_then(signed_number).then(
-        dest
+        dest.clone()
         .separated_by(just(Token::Comma))
         .delimited_by(just(Token::LParen), just(Token::RParen))
     ).then_ignore(just(Token::Dot))
@@ -112,6 +203,48 @@ fn parser() -> impl Parser<Token, Spanned<Object>, Error=Simple<Token>> + Clone
         Ok(Instruction::FFIBegin(n_bytes, real_destinations))
     });
 
+    // copy bp-16 Q = bp-8 Q.
+    // copy bp-48 B = bp-24 B, 24.  (a Count of 24)
+    let copy = just(Token::KWCopy).ignore_then(dest).then_ignore(just(Token::Equals)).then(src.clone())
+        .then(just(Token::Comma).ignore_then(select! { Token::Number(n) => n }).or_not())
+        .then_ignore(just(Token::Dot))
+        .map(|((dest, src), count)| Instruction::Copy(dest, src, Count(count.unwrap_or(1))));
+
+    // label 0.
+    // jif bp-8 Q, 0.
+    // jmp 0.
+    let label_here = just(Token::KWLabel).ignore_then(label).then_ignore(just(Token::Dot)).map(Instruction::Label);
+    let jif = just(Token::KWJif).ignore_then(src.clone()).then_ignore(just(Token::Comma)).then(label).then_ignore(just(Token::Dot))
+        .map(|(src, label)| Instruction::JIf(src, label));
+    let jmp = just(Token::KWJmp).ignore_then(label).then_ignore(just(Token::Dot)).map(|label| Instruction::JIf(Src::Imm(1), label));
+
+    // ffiret bp-8 Q.
+    let ffi_ret = just(Token::KWFFIRet).ignore_then(src).then_ignore(just(Token::Dot)).map(Instruction::FFIRet);
+
+    // data name "some text".
+    // data name align 8 [1, 2, 3].
+    // (globals are the same, but spelled "global")
+    let bytes = choice((
+        select! { Token::Str(s) => s.into_bytes() },
+        select! { Token::Number(n) => n }
+            .try_map(|n, span: Span| u8::try_from(n).map_err(|_| Simple::custom(span, "bytes have to fit in a byte")))
+            .separated_by(just(Token::Comma))
+            .delimited_by(just(Token::LBrack), just(Token::RBrack)),
+    ));
+
+    let blob = select! { Token::Identifier(name) => name }
+        .then(just(Token::KWAlign).ignore_then(select! { Token::Number(n) => n }).or_not())
+        .then(bytes)
+        .then_ignore(just(Token::Dot))
+        .try_map(|((name, align), bytes), span: Span| {
+            let align = align.unwrap_or(1);
+            if !align.is_power_of_two() { return Err(Simple::custom(span, "alignment has to be a power of two")) }
+            Ok(Blob { name, align, bytes })
+        });
+
+    let data = just(Token::KWData).ignore_then(blob.clone());
+    let global = just(Token::KWGlobal).ignore_then(blob);
+
     /*
     let instruction = choice((
         // FFIBegin
@@ -121,7 +254,142 @@ fn parser() -> impl Parser<Token, Spanned<Object>, Error=Simple<Token>> + Clone
     
     instruction.repeated()
     */
-    ffi_begin.map_with_span(|i, s| (i, s)).repeated()
-    .map(|instructions| Object { instructions: instructions.into_iter().map(|(x, span)| x).collect() })
+    choice((
+        ffi_begin.map(Item::Instruction),
+        copy.map(Item::Instruction),
+        label_here.map(Item::Instruction),
+        jif.map(Item::Instruction),
+        jmp.map(Item::Instruction),
+        ffi_ret.map(Item::Instruction),
+        data.map(Item::Data),
+        global.map(Item::Global),
+    )).map_with_span(|i, s| (i, s)).repeated()
+    .try_map(|items, _| {
+        let mut object = Object { instructions: vec![], data: vec![], globals: vec![] };
+        for (item, span) in items {
+            match item {
+                Item::Instruction(i) => object.instructions.push(i),
+                Item::Data(blob) => {
+                    if object.data.iter().any(|b| b.name == blob.name) {
+                        return Err(Simple::custom(span, format!("data defined twice: {}", blob.name)))
+                    }
+                    object.data.push(blob)
+                }
+                Item::Global(blob) => {
+                    if object.globals.iter().any(|b| b.name == blob.name) {
+                        return Err(Simple::custom(span, format!("global defined twice: {}", blob.name)))
+                    }
+                    object.globals.push(blob)
+                }
+            }
+        }
+        Ok(object)
+    })
     .map_with_span(|o, s| (o, s))
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::interpreter_fn::InterpreterFn;
+    use super::*;
+
+    #[test]
+    fn parses_what_the_debugger_needs() {
+        // 1 + 2 + .. + (arg0 - 1)
+        let (object, _) = parse("
+            ffinyeh 24 (bp-8 Q, _).
+            copy bp-16 Q = 0.
+            label 0.
+            copy bp-8 Q = bp-8 Q.   % nothing, but a place on both sides
+            jif bp-8 Q, 1.
+            jmp 2.
+            label 1.
+            copy bp-24 B = bp-8 B, 8.
+            copy bp-8 Q = -1.
+            ffiret bp-8 Q.
+            label 2.
+            ffiret bp-16 Q.
+            data d \"some text\".
+        ").unwrap();
+
+        // (instructions aren't PartialEq)
+        assert_eq!(format!("{:?}", object.instructions), format!("{:?}", vec![
+            Instruction::FFIBegin(24, [Dest::Here(-8, Size::Q), Dest::Nowhere, Dest::Nowhere, Dest::Nowhere, Dest::Nowhere, Dest::Nowhere]),
+            Instruction::Copy(Dest::Here(-16, Size::Q), Src::Imm(0), Count(1)),
+            Instruction::Label(Label(0)),
+            Instruction::Copy(Dest::Here(-8, Size::Q), Src::Here(-8, Size::Q), Count(1)),
+            Instruction::JIf(Src::Here(-8, Size::Q), Label(1)),
+            Instruction::JIf(Src::Imm(1), Label(2)),
+            Instruction::Label(Label(1)),
+            Instruction::Copy(Dest::Here(-24, Size::B), Src::Here(-8, Size::B), Count(8)),
+            Instruction::Copy(Dest::Here(-8, Size::Q), Src::Imm(u64::MAX), Count(1)),
+            Instruction::FFIRet(Src::Here(-8, Size::Q)),
+            Instruction::Label(Label(2)),
+            Instruction::FFIRet(Src::Here(-16, Size::Q)),
+        ]));
+        assert_eq!(object.data.len(), 1);
+
+        let interp = InterpreterFn::new(object, 1024);
+        assert_eq!(interp.run(0, 0, 0, 0, 0, 0).unwrap(), 0);
+        assert_eq!(interp.run(5, 0, 0, 0, 0, 0).unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn names_can_start_with_keywords() {
+        for name in ["database", "bpm", "labels", "alignment", "globals", "copying", "jmps", "data_", "_x"] {
+            let (object, _) = parse(&format!("data {} \"x\". global {} align 8 [1].", name, name))
+                .unwrap_or_else(|error| panic!("{}: {}", name, error));
+            assert_eq!(object.data[0].name, name);
+            assert_eq!((object.globals[0].name.as_str(), object.globals[0].align), (name, 8));
+        }
+        let (object, _) = parse("ffinyeh 8 (_). copy bp-8 Q = data database. ffiret global globals+8 B. data database \"x\". global globals [0].").unwrap();
+        assert_eq!(format!("{:?}", &object.instructions[1..]), format!("{:?}", [
+            Instruction::Copy(Dest::Here(-8, Size::Q), Src::DataAddr(DataId(0)), Count(1)),
+            Instruction::FFIRet(Src::Global(GlobalId(0), 8, Size::B)),
+        ]));
+    }
+
+    #[test]
+    fn data_and_globals_as_operands() {
+        // data can be named before it's declared
+        let (object, _) = parse("
+            ffinyeh 16 (_).
+            copy bp-8 Q = data message.
+            copy bp-16 Q = global count Q.
+            copy global count Q = global table+1 B.
+            copy global table+4 D = bp-16 D.
+            ffiret global count Q.
+            data message \"hello\".
+            data other [1, 2].
+            global table align 8 [0, 5, 0, 0, 0, 0, 0, 0].
+            global count align 8 [0, 0, 0, 0, 0, 0, 0, 0].
+        ").unwrap();
+        let nowhere = Dest::Nowhere;
+        assert_eq!(format!("{:?}", object.instructions), format!("{:?}", vec![
+            Instruction::FFIBegin(16, [nowhere; 6]),
+            Instruction::Copy(Dest::Here(-8, Size::Q), Src::DataAddr(DataId(0)), Count(1)),
+            Instruction::Copy(Dest::Here(-16, Size::Q), Src::Global(GlobalId(1), 0, Size::Q), Count(1)),
+            Instruction::Copy(Dest::Global(GlobalId(1), 0, Size::Q), Src::Global(GlobalId(0), 1, Size::B), Count(1)),
+            Instruction::Copy(Dest::Global(GlobalId(0), 4, Size::D), Src::Here(-16, Size::D), Count(1)),
+            Instruction::FFIRet(Src::Global(GlobalId(1), 0, Size::Q)),
+        ]));
+        assert_eq!(object.global("count"), Some(GlobalId(1)));
+
+        // the second run sees the count the first one left behind
+        let interp = InterpreterFn::new(object, 1024);
+        assert_eq!(interp.run(0, 0, 0, 0, 0, 0).unwrap(), 5);
+        assert_eq!(interp.read_global(GlobalId(0)), [0, 5, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(interp.run(0, 0, 0, 0, 0, 0).unwrap(), 5);
+        assert_eq!(interp.read_global(GlobalId(0)), [0, 5, 0, 0, 5, 0, 0, 0]);
+
+        assert!(parse("ffinyeh 8 (_). ffiret data nothing.").is_err());
+        assert!(parse("ffinyeh 8 (_). ffiret global nothing Q. data nothing [0].").is_err());
+    }
+
+    #[test]
+    fn rejects_what_it_doesnt_know() {
+        assert!(parse("copy bp-8 X = 0.").is_err());
+        assert!(parse("jif 1 0.").is_err());
+        assert!(parse("copy bp-8 Q = 0").is_err());
+        assert!(parse("copy bp-5000000000 Q = 0.").is_err());
+    }
+}
@@ -0,0 +1,56 @@
+// how IR stops early, in either engine
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Trap {
+    pub code: TrapCode,
+    pub ir_index: usize,  // of the instruction that trapped (for OutOfFuel and Interrupted, the first one that didn't run)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TrapCode {
+    User(u64),  // Instruction::Trap
+    OutOfFuel,
+    Interrupted,  // see JitFn::run_with_handle
+    OutOfBounds,  // a pointer outside the sandbox's linear memory
+    Corrupted(u64),  // the JIT's Runtime said it trapped with this kind, which there isn't (the code must have written over it)
+}
+
+impl TrapCode {
+    // JIT code reports a trap as two u64s: a kind (0 means it didn't trap) and a payload
+    pub(crate) fn to_raw(self) -> (u64, u64) {
+        match self {
+            TrapCode::User(code) => (1, code),
+            TrapCode::OutOfFuel => (2, 0),
+            TrapCode::Interrupted => (3, 0),
+            TrapCode::OutOfBounds => (4, 0),
+            TrapCode::Corrupted(_) => unreachable!("nothing traps with Corrupted, it's what from_raw makes of nonsense"),
+        }
+    }
+
+    // None if it didn't trap. the Runtime is writable, so the kind could be anything
+    pub(crate) fn from_raw(kind: u64, payload: u64) -> Option<TrapCode> {
+        match kind {
+            0 => None,
+            1 => Some(TrapCode::User(payload)),
+            2 => Some(TrapCode::OutOfFuel),
+            3 => Some(TrapCode::Interrupted),
+            4 => Some(TrapCode::OutOfBounds),
+            _ => Some(TrapCode::Corrupted(kind)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_trap_codes() {
+        for code in [TrapCode::User(0), TrapCode::User(u64::MAX), TrapCode::OutOfFuel, TrapCode::Interrupted, TrapCode::OutOfBounds] {
+            let (kind, payload) = code.to_raw();
+            assert_eq!(TrapCode::from_raw(kind, payload), Some(code));
+        }
+        assert_eq!(TrapCode::from_raw(0, 1), None);
+        assert_eq!(TrapCode::from_raw(5, 0), Some(TrapCode::Corrupted(5)));
+        assert_eq!(TrapCode::from_raw(u64::MAX, 0), Some(TrapCode::Corrupted(u64::MAX)));
+    }
+}